@@ -0,0 +1,547 @@
+use crate::cache::AnalysisCache;
+use crate::data::{
+    stable_hash, AmbiguityError, AnalysisError, AnalysisRequest, AnalysisResult, Combo, Phonem,
+    Reading, SearchQuery, VideoVocabulary, WordStatus, CUT_TOLERANCE,
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub type AnalysisFuture = BoxFuture<'static, Result<AnalysisResult, AnalysisError>>;
+pub type BatchFuture = BoxFuture<'static, Vec<Result<AnalysisResult, AnalysisError>>>;
+pub type DiagnosisFuture = BoxFuture<'static, Option<Vec<WordStatus>>>;
+pub type OccurrencesFuture = BoxFuture<'static, Vec<Phonem>>;
+pub type SearchFuture = BoxFuture<'static, Vec<Combo>>;
+pub type LanguagesFuture = BoxFuture<'static, Vec<String>>;
+pub type WordsFuture = BoxFuture<'static, Vec<String>>;
+pub type VocabularyFuture = BoxFuture<'static, Option<VideoVocabulary>>;
+
+/// Turns a sentence into combos of phonems taken from the source videos
+pub trait Analyzer: Send + Sync {
+    fn analyze(&self, request: AnalysisRequest) -> AnalysisFuture;
+
+    /// Results of this analyzer, which no other analyzer may use
+    fn cache(&self) -> &Mutex<AnalysisCache>;
+
+    /// Analyses of several requests, in order. Analyzers which cannot batch
+    /// analyze the requests one after the other.
+    fn analyze_batch(&self, requests: Vec<AnalysisRequest>) -> BatchFuture {
+        let analyses: Vec<_> = requests.into_iter().map(|r| self.analyze(r)).collect();
+        Box::pin(async move {
+            let mut results = Vec::with_capacity(analyses.len());
+            for analysis in analyses {
+                results.push(analysis.await);
+            }
+            results
+        })
+    }
+
+    /// Status of every word of `words(&request.sentence)`, in order.
+    /// `None` if the analyzer only reports the first problem of a sentence.
+    fn diagnose(&self, _request: AnalysisRequest) -> DiagnosisFuture {
+        Box::pin(async { None })
+    }
+
+    /// Every occurrence of a phonem in the videos, `urls` being the video ids.
+    /// Analyzers which cannot locate single phonems find none.
+    fn occurrences(&self, _urls: Vec<String>, _phonem: String) -> OccurrencesFuture {
+        Box::pin(async { vec![] })
+    }
+
+    /// Every occurrence of a word or of a sequence of phonems in the videos
+    fn search(&self, urls: Vec<String>, query: SearchQuery) -> SearchFuture {
+        match query {
+            SearchQuery::Phonems(phonems) if phonems.len() == 1 => {
+                let occurrences = self.occurrences(urls, phonems[0].clone());
+                Box::pin(async move { occurrences.await.into_iter().map(|p| vec![p]).collect() })
+            }
+            _ => Box::pin(async { vec![] }),
+        }
+    }
+
+    /// Languages the analyzer supports, empty if it cannot tell
+    fn languages(&self) -> LanguagesFuture {
+        Box::pin(async { vec![] })
+    }
+
+    /// Words which sound like `word`, most similar first
+    fn similar_words(
+        &self,
+        _urls: Vec<String>,
+        _language: Option<String>,
+        _word: String,
+    ) -> WordsFuture {
+        Box::pin(async { vec![] })
+    }
+
+    /// Words and phonems said in the video `video` of `urls`,
+    /// `None` if the analyzer cannot list them
+    fn vocabulary(&self, _urls: Vec<String>, _video: u8) -> VocabularyFuture {
+        Box::pin(async { None })
+    }
+}
+
+/// A word of a sentence, lowercase and without punctuation
+pub struct Word {
+    pub text: String,
+    /// Offset of the first character, in characters
+    pub start: usize,
+    /// Offset after the last character, in characters
+    pub end: usize,
+}
+
+pub fn tokenize(sentence: &str) -> Vec<Word> {
+    let chars: Vec<char> = sentence.chars().collect();
+    let mut words = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < chars.len() && !chars[end].is_whitespace() {
+            end += 1;
+        }
+        // Punctuation around the word is not part of it
+        let start = (i..end).find(|&j| chars[j].is_alphanumeric());
+        let stop = (i..end).rev().find(|&j| chars[j].is_alphanumeric());
+        if let (Some(start), Some(stop)) = (start, stop) {
+            words.push(Word {
+                text: chars[start..=stop]
+                    .iter()
+                    .collect::<String>()
+                    .to_lowercase(),
+                start,
+                end: stop + 1,
+            });
+        }
+        i = end;
+    }
+    words
+}
+
+/// Splits a sentence into lowercase words, without punctuation
+pub fn words(sentence: &str) -> Vec<String> {
+    tokenize(sentence).into_iter().map(|w| w.text).collect()
+}
+
+/// Levenshtein distance, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[derive(Deserialize)]
+struct Fixture {
+    /// Every occurrence of each word, as a list of phonems
+    words: HashMap<String, Vec<Combo>>,
+    /// Possible readings of ambiguous words. The occurrences of a reading
+    /// are looked up in `words` once it is chosen.
+    #[serde(default)]
+    ambiguous: HashMap<String, Vec<Reading>>,
+    /// Every occurrence of each phonem, used for the dictionary entries
+    #[serde(default)]
+    phonems: HashMap<String, Vec<Phonem>>,
+    #[serde(default = "default_combos")]
+    combos: usize,
+    #[serde(default)]
+    languages: Vec<String>,
+}
+
+fn default_combos() -> usize {
+    1
+}
+
+/// Deterministic analyzer reading its phonems from a json fixture:
+///
+/// ```json
+/// {
+///     "words": {"hello": [[{"v": 0, "s": 1.0, "e": 1.2}, {"v": 0, "s": 1.2, "e": 1.4}]]},
+///     "ambiguous": {"read": [{"reading": "reed", "phonems": ["r", "i", "d"]}]},
+///     "phonems": {"r": [{"v": 0, "s": 2.0, "e": 2.1}]},
+///     "combos": 3,
+///     "languages": ["en", "fr"]
+/// }
+/// ```
+///
+/// Combo `k` picks, for the word `i`, the occurrence `(hash(seed) + k + i) % n`
+/// among the occurrences that belong to the requested videos.
+///
+/// Its results are only cached in memory, so that they are never taken for
+/// the ones of sm-interface.
+pub struct MockAnalyzer {
+    fixture: Fixture,
+    cache: Mutex<AnalysisCache>,
+}
+
+impl MockAnalyzer {
+    pub fn from_fixture(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(path)?;
+        Ok(Self::from_json(&bytes)?)
+    }
+
+    pub fn from_json(json: &[u8]) -> serde_json::Result<Self> {
+        Ok(MockAnalyzer {
+            fixture: serde_json::from_slice(json)?,
+            cache: Mutex::new(AnalysisCache::in_memory()),
+        })
+    }
+
+    fn word_occurrences(&self, word: &str, video_count: usize) -> Vec<Combo> {
+        self.fixture
+            .words
+            .get(word)
+            .map(|occurrences| {
+                occurrences
+                    .iter()
+                    .filter(|o| o.iter().all(|p| (p.video_index as usize) < video_count))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn phonem_occurrences(&self, phonem: &str, video_count: usize) -> Vec<&Phonem> {
+        self.fixture
+            .phonems
+            .get(phonem)
+            .map(|occurrences| {
+                occurrences
+                    .iter()
+                    .filter(|p| (p.video_index as usize) < video_count)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sequences of phonems said in a row in a same video
+    fn phonem_sequences(&self, phonems: &[String], video_count: usize) -> Vec<Combo> {
+        let mut sequences: Vec<Combo> = vec![vec![]];
+        for phonem in phonems {
+            let occurrences = self.phonem_occurrences(phonem, video_count);
+            sequences = sequences
+                .iter()
+                .flat_map(|sequence| {
+                    occurrences
+                        .iter()
+                        .filter(move |p| match sequence.last() {
+                            Some(last) => {
+                                last.video_index == p.video_index
+                                    && (p.start - last.end).abs() <= CUT_TOLERANCE
+                            }
+                            None => true,
+                        })
+                        .map(move |p| {
+                            let mut sequence = sequence.clone();
+                            sequence.push(Phonem {
+                                label: Some(phonem.clone()),
+                                ..(*p).clone()
+                            });
+                            sequence
+                        })
+                })
+                .collect();
+        }
+        sequences.retain(|s| !s.is_empty());
+        sequences
+    }
+
+    /// Occurrences of a word built phonem by phonem: the occurrence `j` is made
+    /// of the `j`-th occurrence of every phonem
+    fn spell(&self, phonems: &[String], video_count: usize) -> Vec<Combo> {
+        let occurrences: Vec<_> = phonems
+            .iter()
+            .map(|p| self.phonem_occurrences(p, video_count))
+            .collect();
+        let count = occurrences.iter().map(|o| o.len()).min().unwrap_or(0);
+        (0..count)
+            .map(|j| occurrences.iter().map(|o| o[j].clone()).collect())
+            .collect()
+    }
+
+    /// Occurrences of a word, or the reason why it cannot be said
+    fn lookup(&self, word: &str, request: &AnalysisRequest) -> Result<Vec<Combo>, AmbiguityError> {
+        let video_count = request.urls.len();
+        if let Some(phonems) = request.options.dictionary.get(word) {
+            return match self.spell(phonems, video_count) {
+                o if o.is_empty() => Err(AmbiguityError {
+                    word: word.to_owned(),
+                    candidates: vec![],
+                }),
+                o => Ok(o),
+            };
+        }
+
+        let readings = &request.options.readings;
+        let key = match (self.fixture.ambiguous.get(word), readings.get(word)) {
+            (Some(_), Some(reading)) => reading,
+            (Some(candidates), None) => {
+                return Err(AmbiguityError {
+                    word: word.to_owned(),
+                    candidates: candidates.clone(),
+                })
+            }
+            (None, _) => word,
+        };
+        match self.word_occurrences(key, video_count) {
+            o if o.is_empty() => Err(AmbiguityError {
+                word: word.to_owned(),
+                candidates: vec![],
+            }),
+            o => Ok(o),
+        }
+    }
+
+    /// Occurrences of the word `index` of the sentence allowed by the markup constraints
+    fn constrained_lookup(
+        &self,
+        index: usize,
+        word: &str,
+        request: &AnalysisRequest,
+    ) -> Result<Vec<Combo>, AmbiguityError> {
+        let mut occurrences = self.lookup(word, request)?;
+        let constraint = match request.options.constraints.iter().find(|c| c.word == index) {
+            Some(c) => c,
+            None => return Ok(occurrences),
+        };
+        if let Some(video) = constraint.video {
+            occurrences.retain(|o| o.iter().all(|p| p.video_index == video));
+        }
+        if constraint.longest {
+            let duration = |o: &Combo| o.iter().map(|p| p.end - p.start).sum::<f64>();
+            occurrences = occurrences
+                .into_iter()
+                // Durations read from a fixture may be NaN
+                .max_by(|a, b| {
+                    duration(a)
+                        .partial_cmp(&duration(b))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .into_iter()
+                .collect();
+        }
+        match occurrences {
+            o if o.is_empty() => Err(AmbiguityError {
+                word: word.to_owned(),
+                candidates: vec![],
+            }),
+            o => Ok(o),
+        }
+    }
+
+    fn run(&self, request: &AnalysisRequest) -> Result<AnalysisResult, AmbiguityError> {
+        let words = words(&request.sentence);
+        let occurrences = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| self.constrained_lookup(i, word, request))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let seed = stable_hash(&[&request.seed]) as usize;
+        let combos = (0..self.fixture.combos.max(1))
+            .map(|k| {
+                occurrences
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, o)| {
+                        o[seed.wrapping_add(k).wrapping_add(i) % o.len()]
+                            .iter()
+                            .map(move |p| Phonem {
+                                word: Some(i),
+                                ..p.clone()
+                            })
+                    })
+                    .collect::<Vec<Phonem>>()
+            })
+            .collect();
+        Ok(AnalysisResult {
+            combos,
+            scores: None,
+        })
+    }
+}
+
+impl Analyzer for MockAnalyzer {
+    fn analyze(&self, request: AnalysisRequest) -> AnalysisFuture {
+        let res = self.run(&request).map_err(AnalysisError::from);
+        Box::pin(async move { res })
+    }
+
+    fn cache(&self) -> &Mutex<AnalysisCache> {
+        &self.cache
+    }
+
+    fn diagnose(&self, request: AnalysisRequest) -> DiagnosisFuture {
+        let statuses = words(&request.sentence)
+            .iter()
+            .enumerate()
+            .map(
+                |(i, word)| match self.constrained_lookup(i, word, &request) {
+                    Ok(_) => WordStatus::Ok,
+                    Err(e) if e.candidates.is_empty() => WordStatus::Unavailable,
+                    Err(e) => WordStatus::Ambiguous {
+                        candidates: e.candidates,
+                    },
+                },
+            )
+            .collect();
+        Box::pin(async move { Some(statuses) })
+    }
+
+    fn occurrences(&self, urls: Vec<String>, phonem: String) -> OccurrencesFuture {
+        let occurrences = self
+            .phonem_occurrences(&phonem, urls.len())
+            .into_iter()
+            .map(|p| Phonem {
+                label: Some(phonem.clone()),
+                ..p.clone()
+            })
+            .collect();
+        Box::pin(async move { occurrences })
+    }
+
+    fn search(&self, urls: Vec<String>, query: SearchQuery) -> SearchFuture {
+        let hits = match query {
+            SearchQuery::Word(word) => self.word_occurrences(&word.to_lowercase(), urls.len()),
+            SearchQuery::Phonems(phonems) => self.phonem_sequences(&phonems, urls.len()),
+        };
+        Box::pin(async move { hits })
+    }
+
+    fn languages(&self) -> LanguagesFuture {
+        let languages = self.fixture.languages.clone();
+        Box::pin(async move { languages })
+    }
+
+    /// Words of the fixture at an edit distance of at most 2
+    fn similar_words(&self, urls: Vec<String>, _: Option<String>, word: String) -> WordsFuture {
+        let mut similar: Vec<_> = self
+            .fixture
+            .words
+            .keys()
+            .filter(|w| **w != word && !self.word_occurrences(w, urls.len()).is_empty())
+            .map(|w| (edit_distance(w, &word), w.clone()))
+            .filter(|(d, _)| *d <= 2)
+            .collect();
+        similar.sort();
+        Box::pin(async move { similar.into_iter().map(|(_, w)| w).collect() })
+    }
+
+    fn vocabulary(&self, _: Vec<String>, video: u8) -> VocabularyFuture {
+        let mut vocabulary = VideoVocabulary::default();
+        for (word, occurrences) in self.fixture.words.iter() {
+            let count = occurrences
+                .iter()
+                .filter(|o| !o.is_empty() && o.iter().all(|p| p.video_index == video))
+                .count();
+            if count > 0 {
+                vocabulary.words.insert(word.clone(), count);
+            }
+        }
+        for (phonem, occurrences) in self.fixture.phonems.iter() {
+            let count = occurrences
+                .iter()
+                .filter(|p| p.video_index == video)
+                .count();
+            if count > 0 {
+                vocabulary.phonems.insert(phonem.clone(), count);
+            }
+        }
+        Box::pin(async move { Some(vocabulary) })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Analyzer of `fixtures/analyzer.json`
+    pub fn fixture_analyzer() -> MockAnalyzer {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/analyzer.json");
+        MockAnalyzer::from_fixture(path.as_ref()).unwrap()
+    }
+
+    fn request(sentence: &str) -> AnalysisRequest {
+        AnalysisRequest {
+            sentence: sentence.to_owned(),
+            seed: "seed".to_owned(),
+            urls: vec!["a".to_owned(), "b".to_owned()],
+            options: Default::default(),
+        }
+    }
+
+    #[test]
+    fn mock_analysis_is_deterministic() {
+        let analyzer = fixture_analyzer();
+        let run = || futures::executor::block_on(analyzer.analyze(request("hello world")));
+        let first = run().unwrap();
+        assert_eq!(first.combos.len(), 2);
+        assert_eq!(first.combos, run().unwrap().combos);
+        // Every phonem knows its word
+        let words: Vec<_> = first.combos[0].iter().map(|p| p.word).collect();
+        assert_eq!(words, vec![Some(0), Some(0), Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn mock_reports_missing_and_ambiguous_words() {
+        let analyzer = fixture_analyzer();
+        let missing = futures::executor::block_on(analyzer.analyze(request("hello there")));
+        let missing = match missing.unwrap_err() {
+            AnalysisError::Ambiguity(e) => e,
+            AnalysisError::Timeout => unreachable!(),
+        };
+        assert_eq!(missing.word, "there");
+        assert!(missing.candidates.is_empty());
+
+        let ambiguous = futures::executor::block_on(analyzer.analyze(request("read")));
+        let ambiguous = match ambiguous.unwrap_err() {
+            AnalysisError::Ambiguity(e) => e,
+            AnalysisError::Timeout => unreachable!(),
+        };
+        let readings: Vec<_> = ambiguous
+            .candidates
+            .into_iter()
+            .map(|c| c.reading)
+            .collect();
+        assert_eq!(readings, vec!["reed", "red"]);
+
+        let mut chosen = request("read");
+        chosen
+            .options
+            .readings
+            .insert("read".to_owned(), "red".to_owned());
+        let analysis = futures::executor::block_on(analyzer.analyze(chosen)).unwrap();
+        assert_eq!(analysis.combos[0][0].video_index, 1);
+    }
+
+    #[test]
+    fn mock_diagnoses_every_word() {
+        let analyzer = fixture_analyzer();
+        let statuses =
+            futures::executor::block_on(analyzer.diagnose(request("hello there read"))).unwrap();
+        assert!(matches!(statuses[0], WordStatus::Ok));
+        assert!(matches!(statuses[1], WordStatus::Unavailable));
+        assert!(matches!(statuses[2], WordStatus::Ambiguous { .. }));
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("world", "world"), 0);
+        assert_eq!(edit_distance("wordl", "world"), 2);
+        assert_eq!(edit_distance("word", "world"), 1);
+        assert_eq!(edit_distance("", "red"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("été", "ete"), 2);
+    }
+}
@@ -0,0 +1,549 @@
+use crate::data::{AnalysisId, AnalysisResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const CACHE_FOLDER: &str = ".analysis";
+/// Hashes of the entries, from the least to the most recently used
+const INDEX_FILE: &str = "index.json";
+/// Maximum number of analysis results kept on disk
+const DEFAULT_CAPACITY: usize = 4096;
+/// Maximum number of analysis results kept loaded in memory
+const DEFAULT_MEMORY_CAPACITY: usize = 256;
+
+/// Tells apart the temporary files of the entries being written
+static NEXT_WRITE: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    /// Value of the cache clock the last time this entry was used
+    last_used: u64,
+    /// Value of the cache clock when this entry was added, to tell it apart
+    /// from an entry added again with the same hash
+    generation: u64,
+    /// Loaded lazily from disk, and dropped when memory is needed
+    value: Option<Loaded>,
+}
+
+struct Loaded {
+    key: AnalysisId,
+    value: Arc<AnalysisResult>,
+}
+
+/// Content of `<hash>.json`. The key tells apart the analyses whose keys
+/// have the same hash.
+#[derive(Serialize)]
+struct StoredEntry<'a> {
+    key: &'a AnalysisId,
+    value: &'a AnalysisResult,
+}
+
+#[derive(Deserialize)]
+struct ReadEntry {
+    key: AnalysisId,
+    value: AnalysisResult,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub loaded: usize,
+}
+
+/// Where an analysis can be found
+enum Lookup {
+    /// In memory
+    Loaded(Arc<AnalysisResult>),
+    /// On disk only, with the generation of the entry
+    Stored(PathBuf, u64),
+    Missing,
+}
+
+/// Analysis results cache, persisted in `.analysis/` and evicted with a LRU policy.
+///
+/// Every entry is stored on disk as `<hash>.json`, where `<hash>` is the stable
+/// hash of its `AnalysisId`, so that the cache survives restarts. The order of
+/// use is kept in memory, and saved in `index.json` on eviction and shutdown.
+/// The files missing from the index are the most recent ones, ordered by
+/// modification time.
+///
+/// The files are read and written by `get` and `insert` without holding the
+/// lock. They are only renamed into place or removed under the lock, along
+/// with the update of the index, so that the index and the folder stay in sync.
+pub struct AnalysisCache {
+    entries: HashMap<u64, Entry>,
+    clock: u64,
+    capacity: usize,
+    memory_capacity: usize,
+    /// `None` if the entries are only kept in memory
+    folder: Option<PathBuf>,
+    hits: u64,
+    misses: u64,
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+impl AnalysisCache {
+    fn new(folder: Option<PathBuf>, capacity: usize, memory_capacity: usize) -> Self {
+        AnalysisCache {
+            entries: HashMap::new(),
+            clock: 0,
+            capacity: capacity.max(1),
+            memory_capacity: memory_capacity.max(1),
+            folder,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Loads the index of the cache folder.
+    /// Limits can be set with `ANALYSIS_CACHE_SIZE` and `ANALYSIS_CACHE_MEMORY`.
+    pub fn open() -> Self {
+        let mut folder = std::env::current_dir().expect("Could not access current directory");
+        folder.push(CACHE_FOLDER);
+        if let Err(e) = std::fs::create_dir_all(&folder) {
+            println!("Cannot create the analysis cache folder: {}", e);
+        }
+        Self::open_in(
+            folder,
+            env_or("ANALYSIS_CACHE_SIZE", DEFAULT_CAPACITY),
+            env_or("ANALYSIS_CACHE_MEMORY", DEFAULT_MEMORY_CAPACITY),
+        )
+    }
+
+    fn open_in(folder: PathBuf, capacity: usize, memory_capacity: usize) -> Self {
+        let mut cache = Self::new(Some(folder.clone()), capacity, memory_capacity);
+
+        let index: HashMap<u64, usize> = std::fs::read(folder.join(INDEX_FILE))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Vec<u64>>(&bytes).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(rank, hash)| (hash, rank))
+            .collect();
+        // Files written since the index was saved are the most recently used
+        let mut files: Vec<_> = std::fs::read_dir(&folder)
+            .map(|dir| {
+                dir.filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        let path = entry.path();
+                        if path.extension()? != "json" {
+                            return None;
+                        }
+                        let hash = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+                        let modified = entry.metadata().ok()?.modified().ok()?;
+                        let rank = index.get(&hash);
+                        Some(((rank.is_none(), rank.copied(), modified), hash))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        for (_, hash) in files {
+            cache.clock += 1;
+            cache.entries.insert(
+                hash,
+                Entry {
+                    last_used: cache.clock,
+                    generation: cache.clock,
+                    value: None,
+                },
+            );
+        }
+        cache.evict();
+
+        cache
+    }
+
+    /// Cache which is never written to disk, for the analyzers whose results
+    /// must not outlive the process. Its size is set with `ANALYSIS_CACHE_MEMORY`.
+    pub fn in_memory() -> Self {
+        let capacity = env_or("ANALYSIS_CACHE_MEMORY", DEFAULT_MEMORY_CAPACITY);
+        Self::new(None, capacity, capacity)
+    }
+
+    fn path(&self, hash: u64) -> Option<PathBuf> {
+        let mut p = self.folder.as_ref()?.join(hash.to_string());
+        p.set_extension("json");
+        Some(p)
+    }
+
+    fn lookup(&mut self, key: &AnalysisId) -> Lookup {
+        let hash = key.stable_hash();
+        let path = self.path(hash);
+        self.clock += 1;
+        let clock = self.clock;
+
+        let lookup = match self.entries.get_mut(&hash) {
+            None => Lookup::Missing,
+            Some(entry) => {
+                entry.last_used = clock;
+                match (&entry.value, path) {
+                    (Some(loaded), _) if loaded.key == *key => Lookup::Loaded(loaded.value.clone()),
+                    // Another key with the same hash
+                    (Some(_), _) => Lookup::Missing,
+                    (None, Some(path)) => Lookup::Stored(path, entry.generation),
+                    (None, None) => Lookup::Missing,
+                }
+            }
+        };
+        match lookup {
+            Lookup::Loaded(..) => self.hits += 1,
+            Lookup::Missing => self.misses += 1,
+            Lookup::Stored(..) => {}
+        }
+        lookup
+    }
+
+    /// Keeps in memory the analysis read from disk
+    fn loaded(
+        &mut self,
+        key: &AnalysisId,
+        generation: u64,
+        value: AnalysisResult,
+    ) -> Arc<AnalysisResult> {
+        self.hits += 1;
+        let value = Arc::new(value);
+        // The entry may have been evicted or added again in the meantime
+        let entry = self.entries.get_mut(&key.stable_hash());
+        if let Some(entry) = entry.filter(|e| e.generation == generation) {
+            entry.value = Some(Loaded {
+                key: key.clone(),
+                value: value.clone(),
+            });
+            self.unload();
+        }
+        value
+    }
+
+    /// The file of the entry could not be read, or belongs to another key.
+    /// Unreadable files are removed, unless the entry was added again since.
+    fn not_loaded(&mut self, key: &AnalysisId, generation: u64, readable: bool) {
+        self.misses += 1;
+        let hash = key.stable_hash();
+        let current = matches!(self.entries.get(&hash), Some(e) if e.generation == generation);
+        if !readable && current {
+            self.entries.remove(&hash);
+            if let Some(path) = self.path(hash) {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+
+    /// Looks for an analysis in memory only, without any disk access
+    pub fn get_loaded(&mut self, key: &AnalysisId) -> Option<Arc<AnalysisResult>> {
+        match self.lookup(key) {
+            Lookup::Loaded(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Adds an analysis, moving its file written at `part` into place
+    fn add(&mut self, key: &AnalysisId, value: Arc<AnalysisResult>, part: Option<PathBuf>) {
+        let hash = key.stable_hash();
+        if let (Some(part), Some(path)) = (part, self.path(hash)) {
+            if let Err(e) = std::fs::rename(&part, path) {
+                println!("Cannot write the analysis in cache: {}", e);
+                std::fs::remove_file(part).ok();
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(
+            hash,
+            Entry {
+                last_used: self.clock,
+                generation: self.clock,
+                value: Some(Loaded {
+                    key: key.clone(),
+                    value,
+                }),
+            },
+        );
+        self.evict();
+        self.unload();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            loaded: self.entries.values().filter(|e| e.value.is_some()).count(),
+        }
+    }
+
+    /// Writes the order of use of the entries to the index of the folder
+    pub fn save(&self) {
+        let folder = match &self.folder {
+            Some(folder) => folder,
+            None => return,
+        };
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, e)| e.last_used);
+        let hashes: Vec<u64> = entries.into_iter().map(|(hash, _)| *hash).collect();
+        let part = folder.join(INDEX_FILE).with_extension("json.part");
+        let written = serde_json::to_vec(&hashes)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| std::fs::write(&part, bytes))
+            .and_then(|_| std::fs::rename(&part, folder.join(INDEX_FILE)));
+        if let Err(e) = written {
+            println!("Cannot save the analysis cache index: {}", e);
+        }
+    }
+
+    /// Removes the least recently used entries until the capacity is respected,
+    /// along with their files. The files are removed on next start if they cannot be.
+    fn evict(&mut self) {
+        if self.entries.len() <= self.capacity {
+            return;
+        }
+        while self.entries.len() > self.capacity {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(hash, _)| *hash)
+                .unwrap(); // The map is not empty
+            self.entries.remove(&lru);
+            if let Some(path) = self.path(lru) {
+                std::fs::remove_file(path).ok();
+            }
+        }
+        self.save();
+    }
+
+    /// Drops the least recently used values from memory, keeping them on disk
+    fn unload(&mut self) {
+        let mut loaded: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.value.is_some())
+            .map(|(hash, e)| (e.last_used, *hash))
+            .collect();
+        if loaded.len() <= self.memory_capacity {
+            return;
+        }
+        loaded.sort();
+        let excess = loaded.len() - self.memory_capacity;
+        for (_, hash) in loaded.into_iter().take(excess) {
+            if let Some(entry) = self.entries.get_mut(&hash) {
+                entry.value = None;
+            }
+        }
+    }
+}
+
+async fn read(path: &Path) -> Option<ReadEntry> {
+    let bytes = async_fs::read(path).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Writes the entry to a temporary file next to `path`, returning it.
+/// It is moved into place by `AnalysisCache::add`, so that the entry is never
+/// read half written.
+async fn write(path: &Path, key: &AnalysisId, value: &AnalysisResult) -> std::io::Result<PathBuf> {
+    let bytes = serde_json::to_vec(&StoredEntry { key, value })?;
+    let write = NEXT_WRITE.fetch_add(1, Ordering::Relaxed);
+    let part = path.with_extension(format!("json.{}.part", write));
+    if let Err(e) = async_fs::write(&part, bytes).await {
+        async_fs::remove_file(&part).await.ok();
+        return Err(e);
+    }
+    Ok(part)
+}
+
+/// Looks for an analysis, in memory first and then on disk
+pub async fn get(cache: &Mutex<AnalysisCache>, key: &AnalysisId) -> Option<Arc<AnalysisResult>> {
+    let lookup = cache.lock().unwrap().lookup(key); // panics if panic already happened
+    match lookup {
+        Lookup::Loaded(value) => Some(value),
+        Lookup::Stored(path, generation) => match read(&path).await {
+            Some(stored) if stored.key == *key => {
+                Some(cache.lock().unwrap().loaded(key, generation, stored.value))
+                // panics if panic already happened
+            }
+            // Another key with the same hash
+            Some(_) => {
+                cache.lock().unwrap().not_loaded(key, generation, true); // panics if panic already happened
+                None
+            }
+            None => {
+                // The file may have been removed or corrupted
+                cache.lock().unwrap().not_loaded(key, generation, false); // panics if panic already happened
+                None
+            }
+        },
+        Lookup::Missing => None,
+    }
+}
+
+/// The entry is added once its file is written, so that it cannot be evicted
+/// or unloaded before its file exists
+pub async fn insert(cache: &Mutex<AnalysisCache>, key: &AnalysisId, value: Arc<AnalysisResult>) {
+    let path = cache.lock().unwrap().path(key.stable_hash()); // panics if panic already happened
+    let mut part = None;
+    if let Some(path) = path {
+        match write(&path, key, &value).await {
+            Ok(written) => part = Some(written),
+            Err(e) => println!("Cannot write the analysis in cache: {}", e),
+        }
+    }
+    cache.lock().unwrap().add(key, value, part); // panics if panic already happened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{AnalysisRequest, Project, Segment};
+    use futures::executor::block_on;
+
+    fn key(sentence: &str) -> AnalysisId {
+        let project = Project::new("test", "seed", &["a".to_owned()]);
+        AnalysisId::from_request(&AnalysisRequest::new(&project, &Segment::new(sentence)))
+    }
+
+    /// Analysis told apart by its number of combos
+    fn analysis(combos: usize) -> Arc<AnalysisResult> {
+        Arc::new(AnalysisResult {
+            combos: vec![vec![]; combos],
+            scores: None,
+        })
+    }
+
+    fn combos(cache: &Mutex<AnalysisCache>, sentence: &str) -> Option<usize> {
+        block_on(get(cache, &key(sentence))).map(|a| a.combos.len())
+    }
+
+    /// Empty folder, removed when dropped
+    struct Folder(PathBuf);
+
+    impl Folder {
+        fn new(name: &str) -> Self {
+            let folder = std::env::temp_dir().join(format!(
+                "web-sentence-cache-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::remove_dir_all(&folder).ok();
+            std::fs::create_dir_all(&folder).unwrap();
+            Folder(folder)
+        }
+
+        fn open(&self, capacity: usize, memory_capacity: usize) -> Mutex<AnalysisCache> {
+            Mutex::new(AnalysisCache::open_in(
+                self.0.clone(),
+                capacity,
+                memory_capacity,
+            ))
+        }
+    }
+
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = Mutex::new(AnalysisCache::new(None, 2, 2));
+        block_on(insert(&cache, &key("a"), analysis(1)));
+        block_on(insert(&cache, &key("b"), analysis(2)));
+        assert_eq!(combos(&cache, "a"), Some(1));
+        block_on(insert(&cache, &key("c"), analysis(3)));
+        assert_eq!(combos(&cache, "b"), None);
+        assert_eq!(combos(&cache, "a"), Some(1));
+        assert_eq!(combos(&cache, "c"), Some(3));
+        let stats = cache.lock().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 1, 2));
+    }
+
+    #[test]
+    fn unloaded_entries_are_read_from_disk() {
+        let folder = Folder::new("unload");
+        let cache = folder.open(4, 1);
+        block_on(insert(&cache, &key("a"), analysis(1)));
+        block_on(insert(&cache, &key("b"), analysis(2)));
+        assert_eq!(cache.lock().unwrap().stats().loaded, 1);
+        assert!(cache.lock().unwrap().get_loaded(&key("a")).is_none());
+        assert_eq!(combos(&cache, "a"), Some(1));
+        assert!(cache.lock().unwrap().get_loaded(&key("a")).is_some());
+        assert!(cache.lock().unwrap().get_loaded(&key("b")).is_none());
+    }
+
+    #[test]
+    fn order_of_use_is_kept_across_restarts() {
+        let folder = Folder::new("restart");
+        let cache = folder.open(4, 4);
+        for sentence in ["a", "b", "c"].iter() {
+            block_on(insert(&cache, &key(sentence), analysis(1)));
+        }
+        assert_eq!(combos(&cache, "a"), Some(1));
+        // As on shutdown
+        cache.lock().unwrap().save();
+        // Missing from the index
+        block_on(insert(&cache, &key("d"), analysis(1)));
+
+        // "b" then "c" are the least recently used
+        let cache = folder.open(2, 2);
+        assert_eq!(combos(&cache, "b"), None);
+        assert_eq!(combos(&cache, "c"), None);
+        assert_eq!(combos(&cache, "a"), Some(1));
+        assert_eq!(combos(&cache, "d"), Some(1));
+        // The files of "a" and "d", and the index
+        assert_eq!(std::fs::read_dir(&folder.0).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn entries_added_again_keep_their_file() {
+        let folder = Folder::new("again");
+        let cache = folder.open(4, 1);
+        block_on(insert(&cache, &key("a"), analysis(1)));
+        block_on(insert(&cache, &key("b"), analysis(2)));
+        let generation = match cache.lock().unwrap().lookup(&key("a")) {
+            Lookup::Stored(_, generation) => generation,
+            _ => panic!("\"a\" should only be on disk"),
+        };
+        // "a" is added again while its previous file is being read
+        block_on(insert(&cache, &key("a"), analysis(3)));
+        cache
+            .lock()
+            .unwrap()
+            .not_loaded(&key("a"), generation, false);
+        let path = cache.lock().unwrap().path(key("a").stable_hash()).unwrap();
+        assert!(path.exists());
+        assert_eq!(combos(&cache, "a"), Some(3));
+        // No temporary file is left
+        assert_eq!(std::fs::read_dir(&folder.0).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn entries_of_other_keys_are_not_returned() {
+        let folder = Folder::new("collision");
+        let cache = folder.open(4, 4);
+        block_on(insert(&cache, &key("a"), analysis(1)));
+        block_on(insert(&cache, &key("b"), analysis(2)));
+        let path = |sentence: &str| cache.lock().unwrap().path(key(sentence).stable_hash());
+        let (a, b) = (path("a").unwrap(), path("b").unwrap());
+        // As if "b" had the hash of "a"
+        std::fs::copy(&b, &a).unwrap();
+        std::fs::write(&b, "not an analysis").unwrap();
+
+        let cache = folder.open(4, 4);
+        assert_eq!(combos(&cache, "a"), None);
+        assert!(a.exists());
+        // Unreadable files are removed
+        assert_eq!(combos(&cache, "b"), None);
+        assert!(!b.exists());
+        assert_eq!(cache.lock().unwrap().stats().entries, 1);
+    }
+}
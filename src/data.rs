@@ -1,5 +1,9 @@
+use crate::export::{Caption, ExportOptions};
+use crate::markup::{AnalyzedSentence, MarkupError, WordConstraint};
+use ges::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,25 +14,146 @@ pub struct Phonem {
     pub start: f64,
     #[serde(rename = "e")]
     pub end: f64,
+    /// Phonem said, if sm-interface gives it
+    #[serde(rename = "p", default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Index of the word of the sentence this phonem belongs to, if sm-interface gives it
+    #[serde(rename = "w", default, skip_serializing_if = "Option::is_none")]
+    pub word: Option<usize>,
+    /// Silence lasting from `start` to `end`, the video is not used
+    #[serde(rename = "g", default, skip_serializing_if = "std::ops::Not::not")]
+    pub gap: bool,
 }
 
 impl PartialEq for Phonem {
     fn eq(&self, other: &Self) -> bool {
-        self.video_index == other.video_index && self.start == other.start
+        self.video_index == other.video_index
+            && self.start == other.start
+            && self.end == other.end
+            && self.gap == other.gap
     }
 }
 
+impl Eq for Phonem {}
+
 impl std::hash::Hash for Phonem {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.video_index.hash(state);
         ((self.start * 1024.0) as u64).hash(state);
+        ((self.end * 1024.0) as u64).hash(state);
+        self.gap.hash(state);
+    }
+}
+
+impl Phonem {
+    pub fn gap(duration: f64) -> Self {
+        Phonem {
+            video_index: 0,
+            start: 0.0,
+            end: duration,
+            label: None,
+            word: None,
+            gap: true,
+        }
+    }
+
+    /// Whether `other` can replace this phonem in a combo, given the
+    /// duration in seconds of each source video
+    pub fn is_valid_override(&self, other: &Phonem, durations: &[f64]) -> bool {
+        let same_phonem = match (&self.label, &other.label) {
+            (Some(a), Some(b)) => a == b,
+            // Nothing tells that the override says the same phonem
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let duration = match durations.get(other.video_index as usize) {
+            Some(d) => *d,
+            None => return false,
+        };
+        same_phonem && other.start >= 0.0 && other.start < other.end && other.end <= duration
     }
 }
 
 pub type ProjectId = String;
 pub type Seed = String;
 pub type Combo = Vec<Phonem>;
-pub type AnalysisResult = Vec<Combo>;
+
+/// Combos of a sentence, with the ranking score of each combo if sm-interface gives one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "AnalysisOutput")]
+pub struct AnalysisResult {
+    pub combos: Vec<Combo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scores: Option<Vec<f64>>,
+}
+
+/// sm-interface either outputs the bare list of combos, or combos with scores
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnalysisOutput {
+    Combos(Vec<Combo>),
+    Scored {
+        combos: Vec<Combo>,
+        #[serde(default)]
+        scores: Option<Vec<f64>>,
+    },
+}
+
+impl From<AnalysisOutput> for AnalysisResult {
+    fn from(output: AnalysisOutput) -> Self {
+        match output {
+            AnalysisOutput::Combos(combos) => AnalysisResult {
+                combos,
+                scores: None,
+            },
+            AnalysisOutput::Scored { combos, scores } => AnalysisResult { combos, scores },
+        }
+    }
+}
+
+impl AnalysisResult {
+    pub fn score(&self, index: usize) -> Option<f64> {
+        self.scores.as_ref()?.get(index).copied()
+    }
+}
+
+/// Gap between two consecutive phonems of a same video still considered continuous
+pub const CUT_TOLERANCE: f64 = 0.01;
+
+/// Summary of a combo, so that the user can choose one without previewing all of them
+#[derive(Debug, Serialize, Clone)]
+pub struct ComboInfo {
+    pub index: usize,
+    /// In seconds
+    pub duration: f64,
+    pub cuts: usize,
+    /// Indexes of the source videos used, sorted
+    pub videos: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+impl ComboInfo {
+    pub fn new(index: usize, combo: &[Phonem], score: Option<f64>) -> Self {
+        let cuts = combo
+            .windows(2)
+            .filter(|w| {
+                w[0].video_index != w[1].video_index
+                    || (w[1].start - w[0].end).abs() > CUT_TOLERANCE
+            })
+            .count();
+        let mut videos: Vec<u8> = combo.iter().map(|p| p.video_index).collect();
+        videos.sort_unstable();
+        videos.dedup();
+        ComboInfo {
+            index,
+            duration: combo.iter().map(|p| p.end - p.start).sum(),
+            cuts,
+            videos,
+            score,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Video {
@@ -67,6 +192,15 @@ pub fn get_video_path(yt_id: &str, small: bool) -> std::path::PathBuf {
     pwd.join(p)
 }
 
+/// Vocabulary of a video, stored next to its transcoded files
+pub fn get_vocabulary_path(yt_id: &str) -> std::path::PathBuf {
+    let mut p = std::path::PathBuf::from(VIDEO_PATH);
+    p.push(format!("{}_vocabulary", yt_id));
+    p.set_extension("json");
+    let pwd = std::env::current_dir().unwrap(); // Checked at the beginning
+    pwd.join(p)
+}
+
 #[derive(Debug)]
 pub enum VideoError {
     BadPath, // Invalid unicode
@@ -91,6 +225,11 @@ impl Video {
         })
     }
 
+    /// Duration in seconds
+    pub fn duration(&self) -> f64 {
+        self.asset.duration().nseconds() as f64 / 1e9
+    }
+
     pub fn get_path_full_resolution(&self) -> std::path::PathBuf {
         get_video_path(&self.id.id, false)
     }
@@ -108,6 +247,12 @@ pub struct Project {
     pub name: ProjectId,
     #[serde(skip_serializing)]
     pub segments: Vec<Segment>,
+    /// Phonems of the words whose pronunciation is overridden
+    #[serde(default)]
+    pub dictionary: BTreeMap<String, Vec<String>>,
+    /// Language of the source videos, the analyzer default if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
 }
 
 impl PartialEq for Project {
@@ -122,6 +267,28 @@ pub struct Segment {
     pub sentence: String,
     #[serde(rename = "i")]
     pub combo_index: u16,
+    /// Reading chosen for each ambiguous word
+    #[serde(rename = "r", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub readings: BTreeMap<String, String>,
+    /// Phonems of the chosen combo replaced by hand, by position in the combo.
+    /// They are dropped whenever the combo changes.
+    #[serde(rename = "o", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overrides: BTreeMap<usize, Phonem>,
+    /// Replaces the project seed for this segment only
+    #[serde(rename = "d", default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<Seed>,
+    /// The sentence is analyzed as typed, without spelling out numbers and symbols
+    #[serde(
+        rename = "w",
+        alias = "raw",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub raw: bool,
+    /// Combo kept as it was when the segment was frozen, used instead of
+    /// analyzing the sentence again
+    #[serde(rename = "f", default, skip_serializing_if = "Option::is_none")]
+    pub frozen: Option<Combo>,
 }
 
 impl Segment {
@@ -129,8 +296,72 @@ impl Segment {
         Segment {
             sentence: sentence.to_owned(),
             combo_index: 0,
+            readings: BTreeMap::new(),
+            overrides: BTreeMap::new(),
+            seed: None,
+            raw: false,
+            frozen: None,
         }
     }
+
+    /// Sentence given to the analyzer, for the project language.
+    /// Invalid markup is read as plain text.
+    pub fn analyzed_sentence(&self, language: Option<&str>) -> String {
+        self.analysis_input(language, None).text
+    }
+
+    fn analysis_input(
+        &self,
+        language: Option<&str>,
+        video_count: Option<usize>,
+    ) -> AnalyzedSentence {
+        crate::markup::analyze(&self.sentence, self.raw, language, video_count).unwrap_or_else(
+            |_| AnalyzedSentence {
+                text: if self.raw {
+                    self.sentence.clone()
+                } else {
+                    crate::normalize::normalize(&self.sentence, language)
+                },
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Seed used to analyze the segment
+    pub fn effective_seed<'a>(&'a self, project: &'a Project) -> &'a Seed {
+        self.seed.as_ref().unwrap_or(&project.seed)
+    }
+
+    /// The combo as it is rendered, with the overridden phonems replaced and
+    /// the pauses of the markup inserted
+    pub fn rendered_combo(
+        &self,
+        combo: &[Phonem],
+        project: &Project,
+    ) -> Result<Combo, MarkupError> {
+        let combo = combo
+            .iter()
+            .enumerate()
+            .map(|(i, p)| match self.overrides.get(&i) {
+                Some(o) => Phonem {
+                    word: p.word,
+                    ..o.clone()
+                },
+                None => p.clone(),
+            })
+            .collect();
+        crate::markup::insert_pauses(combo, &self.markup(project)?)
+    }
+
+    /// Parses the markup of the sentence
+    pub fn markup(&self, project: &Project) -> Result<AnalyzedSentence, MarkupError> {
+        crate::markup::analyze(
+            &self.sentence,
+            self.raw,
+            project.language.as_deref(),
+            Some(project.video_ids.len()),
+        )
+    }
 }
 
 impl Project {
@@ -143,29 +374,106 @@ impl Project {
                 .map(|u| YoutubeId::new(u.clone()))
                 .collect(),
             segments: Default::default(),
+            dictionary: Default::default(),
+            language: None,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub struct AnalysisId(Seed, String, String);
+/// Everything an analyzer needs to know to analyze a sentence
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisRequest {
+    pub sentence: String,
+    pub seed: Seed,
+    pub urls: Vec<String>,
+    #[serde(flatten)]
+    pub options: AnalysisOptions,
+}
+
+/// Optional parameters of an analysis, empty ones are not serialized
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AnalysisOptions {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub readings: BTreeMap<String, String>,
+    /// Entries of the project dictionary used by the sentence
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dictionary: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Constraints given with the markup of the sentence
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub constraints: Vec<WordConstraint>,
+}
+
+impl AnalysisRequest {
+    pub fn new(project: &Project, segment: &Segment) -> Self {
+        let input =
+            segment.analysis_input(project.language.as_deref(), Some(project.video_ids.len()));
+        let words = crate::analyzer::words(&input.text);
+        AnalysisRequest {
+            sentence: input.text,
+            seed: segment.effective_seed(project).clone(),
+            urls: project.video_ids.iter().map(|id| id.id.clone()).collect(),
+            options: AnalysisOptions {
+                readings: segment.readings.clone(),
+                dictionary: project
+                    .dictionary
+                    .iter()
+                    .filter(|(word, _)| words.contains(word))
+                    .map(|(word, phonems)| (word.clone(), phonems.clone()))
+                    .collect(),
+                language: project.language.clone(),
+                constraints: input.constraints,
+            },
+        }
+    }
+}
+
+/// 64 bits FNV-1a of the fields, separated by a null byte.
+/// Unlike `DefaultHasher`, it does not depend on the compiler version.
+pub fn stable_hash(fields: &[&str]) -> u64 {
+    fields
+        .iter()
+        .flat_map(|field| field.bytes().chain(std::iter::once(0)))
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Effective seed of the segment, videos, sentence and options of an analysis
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct AnalysisId(Seed, String, String, String);
 impl AnalysisId {
-    pub fn from_project_sentence(project: &Project, sentence: &str) -> AnalysisId {
+    pub fn from_request(request: &AnalysisRequest) -> AnalysisId {
+        // Empty when there are no options, so that plain analyses keep their key
+        let options = serde_json::to_string(&request.options).unwrap(); // Always serializable
+        let options = if options == "{}" {
+            String::new()
+        } else {
+            options
+        };
         AnalysisId(
-            project.seed.clone(),
-            project
-                .video_ids
-                .iter()
-                .map(|s| &*s.id)
-                .collect::<Vec<&str>>()
-                .join(""),
-            sentence.to_owned(),
+            request.seed.clone(),
+            request.urls.join(""),
+            request.sentence.clone(),
+            options,
         )
     }
+
+    /// Hash used to name persisted entries
+    pub fn stable_hash(&self) -> u64 {
+        let AnalysisId(seed, videos, sentence, options) = self;
+        if options.is_empty() {
+            stable_hash(&[seed, videos, sentence])
+        } else {
+            stable_hash(&[seed, videos, sentence, options])
+        }
+    }
 }
 
 const PREVIEW_FOLDER: &str = ".preview";
 const RENDER_FOLDER: &str = ".render";
+const AUDIO_FOLDER: &str = ".audio";
 
 #[derive(Debug, PartialEq, Hash)]
 pub struct PreviewId<'a>(String, &'a [Phonem]);
@@ -193,11 +501,27 @@ impl<'a> PreviewId<'a> {
         p.set_extension("mp4");
         p
     }
-    pub fn render_path(&self) -> std::path::PathBuf {
+    /// Every format of export is cached separately, and so are the texts of
+    /// the captions
+    pub fn render_path(&self, options: &ExportOptions, captions: &[Caption]) -> std::path::PathBuf {
         let mut p = std::env::current_dir().expect("Could not access current directory");
-        p.push(RENDER_FOLDER);
-        p.push(self.file_name());
-        p.set_extension("mp4");
+        p.push(if options.audio_only.is_some() {
+            AUDIO_FOLDER
+        } else {
+            RENDER_FOLDER
+        });
+        let mut key = options.cache_key();
+        if !captions.is_empty() {
+            let mut hasher = DefaultHasher::new();
+            captions.hash(&mut hasher);
+            key = format!("{}-{}", key, hasher.finish());
+        }
+        if key.is_empty() {
+            p.push(self.file_name());
+        } else {
+            p.push(format!("{}_{}", self.file_name(), key));
+        }
+        p.set_extension(options.extension());
         p
     }
 }
@@ -209,9 +533,19 @@ impl std::hash::Hash for Project {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// One of the possible pronunciations of an ambiguous word
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reading {
+    pub reading: String,
+    #[serde(default)]
+    pub phonems: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AmbiguityError {
     pub word: String,
+    #[serde(default)]
+    pub candidates: Vec<Reading>,
 }
 
 impl std::fmt::Display for AmbiguityError {
@@ -222,9 +556,166 @@ impl std::fmt::Display for AmbiguityError {
 
 impl std::error::Error for AmbiguityError {}
 
+/// Why a sentence could not be analyzed
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum AnalysisError {
+    /// A word cannot be said by the sources, or has several readings
+    Ambiguity(AmbiguityError),
+    /// The analyzer did not answer in time, the analysis may succeed later
+    Timeout,
+}
+
+impl From<AmbiguityError> for AnalysisError {
+    fn from(e: AmbiguityError) -> Self {
+        AnalysisError::Ambiguity(e)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum WordStatus {
+    Ok,
+    Ambiguous {
+        candidates: Vec<Reading>,
+    },
+    /// The source videos cannot produce this word
+    Unavailable,
+    /// The analyzer did not answer in time
+    TimedOut,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct WordDiagnostic {
+    pub word: String,
+    /// Offsets of the word in the sentence, in characters
+    pub start: usize,
+    pub end: usize,
+    #[serde(flatten)]
+    pub status: WordStatus,
+}
+
+/// What to look for in the source videos
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum SearchQuery {
+    Word(String),
+    Phonems(Vec<String>),
+}
+
+/// An occurrence of a searched word or phonem sequence
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub video_index: u8,
+    /// In seconds
+    pub start: f64,
+    pub end: f64,
+    pub phonems: Combo,
+}
+
+impl SearchHit {
+    pub fn new(phonems: Combo) -> Option<Self> {
+        Some(SearchHit {
+            video_index: phonems.first()?.video_index,
+            start: phonems.first()?.start,
+            end: phonems.last()?.end,
+            phonems,
+        })
+    }
+}
+
+/// Status of a word of a script, with replacements if it cannot be said
+#[derive(Debug, Serialize, Clone)]
+pub struct WordCoverage {
+    #[serde(flatten)]
+    pub diagnostic: WordDiagnostic,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<String>,
+}
+
+/// Number of occurrences of every word and phonem said in a video
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VideoVocabulary {
+    pub words: BTreeMap<String, usize>,
+    pub phonems: BTreeMap<String, usize>,
+}
+
+/// A word or phonem the sources of a project can say
+#[derive(Debug, Serialize, Clone)]
+pub struct VocabularyEntry {
+    pub text: String,
+    pub count: usize,
+    /// Indexes of the source videos saying it, sorted
+    pub videos: Vec<u8>,
+}
+
 #[derive(Serialize)]
 pub struct Preview {
     pub data: String,
     #[serde(flatten)]
     pub segment: Segment,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phonem(video_index: u8, start: f64, end: f64, label: Option<&str>) -> Phonem {
+        Phonem {
+            video_index,
+            start,
+            end,
+            label: label.map(String::from),
+            word: None,
+            gap: false,
+        }
+    }
+
+    #[test]
+    fn overrides_stay_within_their_video() {
+        let original = phonem(0, 1.0, 1.2, Some("a"));
+        let durations = [10.0, 2.0];
+        assert!(original.is_valid_override(&phonem(1, 1.5, 2.0, Some("a")), &durations));
+        assert!(!original.is_valid_override(&phonem(0, 1.5, 2.0, Some("e")), &durations));
+        assert!(!original.is_valid_override(&phonem(2, 1.5, 2.0, Some("a")), &durations));
+        assert!(!original.is_valid_override(&phonem(0, 1.5, 2.0, None), &durations));
+        // Unlabelled phonems can only be checked against the video bounds
+        let unlabelled = phonem(0, 1.0, 1.2, None);
+        assert!(unlabelled.is_valid_override(&phonem(0, 9.0, 10.0, None), &durations));
+        assert!(!unlabelled.is_valid_override(&phonem(1, 1.5, 2.5, None), &durations));
+        assert!(!unlabelled.is_valid_override(&phonem(0, -0.5, 0.5, None), &durations));
+        assert!(!unlabelled.is_valid_override(&phonem(0, 2.0, 2.0, None), &durations));
+    }
+
+    #[test]
+    fn audio_exports_have_a_folder_of_their_own() {
+        use crate::export::{AudioFormat, Container, Quality};
+        let yt_ids = [YoutubeId::new("a".to_owned())];
+        let phonems = [phonem(0, 1.0, 1.2, Some("a"))];
+        let id = PreviewId::from_project_sentence(&yt_ids, &phonems);
+        let audio = ExportOptions {
+            audio_only: Some(AudioFormat::Mp3),
+            ..ExportOptions::default()
+        };
+        let folder = |p: &std::path::Path| p.parent().unwrap().file_name().unwrap().to_owned();
+
+        let path = id.render_path(&audio, &[]);
+        assert_eq!(folder(&path), AUDIO_FOLDER);
+        assert_eq!(path.extension().unwrap(), "mp3");
+        let video = id.render_path(&ExportOptions::default(), &[]);
+        assert_eq!(folder(&video), RENDER_FOLDER);
+        assert_eq!(video.extension().unwrap(), "mp4");
+        // Only the bitrate sets the audio files apart
+        let webm = ExportOptions {
+            container: Container::Webm,
+            ..audio.clone()
+        };
+        assert_eq!(id.render_path(&webm, &[]), path);
+        let high = ExportOptions {
+            quality: Some(Quality::High),
+            ..audio
+        };
+        assert_ne!(id.render_path(&high, &[]), path);
+    }
+}
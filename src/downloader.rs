@@ -1,5 +1,6 @@
 use crate::data::{Video, YoutubeId};
 use crate::error::*;
+use crate::render_pool::{Priority, RENDER_POOL};
 use crate::youtube_dl::{Arg, ResultType, YoutubeDL};
 use actix::*;
 use regex::Regex;
@@ -82,6 +83,48 @@ async fn download_videos(yt_ids: Vec<YoutubeId>) -> Result<(), DownloaderError>
     Err(DownloaderError::DownloadFailedError)
 }
 
+/// Transcodes a downloaded video to the full and small resolutions used by
+/// the renderer. The pipelines run on the render pool.
+async fn transcode_video(yt_id: YoutubeId) -> Result<Arc<Video>, DownloaderError> {
+    let job = move || {
+        let path = get_video_path(&yt_id);
+        // Error while getting video path
+        let path = path.map_err(|_| DownloaderError::VideosFolderNotExistError)?;
+        // No video file matching this video was found
+        let path = path.ok_or(DownloaderError::DowloadedVideoNotFoundError)?;
+
+        crate::renderer::render_main_video(
+            path.as_path(),
+            crate::data::get_video_path(&yt_id.id, false).as_path(),
+            false,
+        )
+        .map_err(|_| DownloaderError::RenderingError)?;
+        crate::renderer::render_main_video(
+            path.as_path(),
+            crate::data::get_video_path(&yt_id.id, true).as_path(),
+            true,
+        )
+        .map_err(|_| DownloaderError::RenderingError)?;
+
+        Video::from(yt_id)
+            .map(Arc::new)
+            .map_err(|_| DownloaderError::BrokenRenderedVideo)
+    };
+    RENDER_POOL
+        .run(Priority::Source, job)
+        .await
+        .map_err(|_| DownloaderError::RenderingError)?
+}
+
+async fn prepare_videos(yt_ids: Vec<YoutubeId>) -> Result<Vec<Arc<Video>>, DownloaderError> {
+    download_videos(yt_ids.clone()).await?;
+    let mut videos = Vec::with_capacity(yt_ids.len());
+    for yt_id in yt_ids {
+        videos.push(transcode_video(yt_id).await?);
+    }
+    Ok(videos)
+}
+
 impl Actor for DownloaderActor {
     type Context = Context<Self>;
 }
@@ -101,45 +144,19 @@ impl Handler<DownloadVideos> for DownloaderActor {
 
             let yt_ids = msg.yt_ids.clone();
 
-            let wrap_download = actix::fut::wrap_future(download_videos(msg.yt_ids));
+            // Transcoding runs on the render pool, the actor only stores the result
+            let wrap_download = actix::fut::wrap_future(prepare_videos(msg.yt_ids));
             let mapped_download = wrap_download.map(
-                move |result: Result<(), DownloaderError>, actor: &mut DownloaderActor, _ctx| {
+                move |result: Result<Vec<Arc<Video>>, DownloaderError>,
+                      actor: &mut DownloaderActor,
+                      _ctx| {
                     match result {
-                        Ok(()) => {
-                            yt_ids.iter().try_for_each(|yt_id| {
+                        Ok(videos) => {
+                            for (yt_id, video) in yt_ids.iter().zip(videos) {
                                 actor.download_states.insert(yt_id.clone(), true);
-
-                                let path = get_video_path(yt_id);
-                                // Error while getting video path
-                                let path =
-                                    path.map_err(|_| DownloaderError::VideosFolderNotExistError)?;
-                                if path.is_none() {
-                                    // No video file matching this video was found
-                                    return Err(DownloaderError::DowloadedVideoNotFoundError);
-                                };
-                                let path = path.unwrap();
-
-                                crate::renderer::render_main_video(
-                                    path.as_path(),
-                                    crate::data::get_video_path(&yt_id.id, false).as_path(),
-                                    false,
-                                )
-                                .map_err(|_| DownloaderError::RenderingError)?;
-                                crate::renderer::render_main_video(
-                                    path.as_path(),
-                                    crate::data::get_video_path(&yt_id.id, true).as_path(),
-                                    true,
-                                )
-                                .map_err(|_| DownloaderError::RenderingError)?;
-
-                                let vid = Arc::new(
-                                    Video::from(yt_id.clone())
-                                        .map_err(|_| DownloaderError::BrokenRenderedVideo)?,
-                                );
-                                actor.videos.insert(yt_id.clone(), vid);
-
-                                Ok(())
-                            })
+                                actor.videos.insert(yt_id.clone(), video);
+                            }
+                            Ok(())
                         }
                         Err(err) => {
                             yt_ids.iter().for_each(|yt_id| {
@@ -159,9 +176,15 @@ impl Handler<DownloadVideos> for DownloaderActor {
     }
 }
 
+/// Matches the files downloaded for the video, and not the other files named
+/// after it like its vocabulary
+fn video_pattern(id: &YoutubeId) -> Regex {
+    let pattern = format!(r"(^|/){}\.(mp4|webm|mkv|flv|3gp)$", regex::escape(&id.id));
+    Regex::new(&pattern).unwrap() // unwrap fails if the pattern is invalid. The id is escaped so never invalid
+}
+
 fn get_video_path(id: &YoutubeId) -> std::io::Result<Option<PathBuf>> {
-    let pattern = format!(r"^.*{}..*$", id.id);
-    let re = Regex::new(&pattern).unwrap(); // unwrap fails if the pattern is invalid. Our pattern is static so never invalid
+    let re = video_pattern(id);
 
     let found_path = fs::read_dir(".videos")?.find(|entry| {
         let entry = entry.as_ref();
@@ -203,3 +226,18 @@ impl Handler<GetVideos> for DownloaderActor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_video_files_are_matched() {
+        let re = video_pattern(&YoutubeId::new("a.b".to_owned()));
+        assert!(re.is_match(".videos/a.b.mp4"));
+        assert!(re.is_match(".videos/a.b.webm"));
+        assert!(!re.is_match(".videos/a.b_vocabulary.json"));
+        assert!(!re.is_match(".videos/xa.b.mp4"));
+        assert!(!re.is_match(".videos/axb.mp4"));
+    }
+}
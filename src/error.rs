@@ -11,8 +11,28 @@ pub enum ServerError {
     ProjectAlreadyExists,
     EmptyUrls,
     SegmentOutOfBounds,
+    ComboIndexOutOfBounds,
+    /// The combo index is not one of the combos of the segment analysis
+    InvalidComboIndex,
     UserAlreadyJoinedProject,
     CommunicationError,
+    InvalidPronunciation,
+    PhonemOutOfBounds,
+    InvalidPhonem,
+    InvalidLanguage,
+    /// The reading is not one of the candidates of the ambiguous word
+    InvalidReading,
+    /// The word to choose a reading for is not in the sentence of the segment
+    WordNotInSentence,
+    SegmentNotAnalyzed,
+    /// Frozen segments must be unfrozen before their analysis can change
+    SegmentFrozen,
+    /// The source videos of the project are still being downloaded
+    VideosNotReady,
+    /// The vocabulary of a source video cannot be built by the analyzer
+    VocabularyUnavailable,
+    RenderJobDoesNotExist,
+    UnsupportedExport(String),
 }
 
 #[derive(Debug)]
@@ -25,4 +45,34 @@ pub enum DownloaderError {
     BrokenRenderedVideo,
 }
 
+#[derive(Debug)]
+pub enum RenderError {
+    /// The GES pipeline failed
+    Pipeline(String),
+    /// The render job ended without giving a result
+    JobLost,
+    Cancelled,
+    /// The animation is heavier than the limit, in bytes
+    TooLarge {
+        size: u64,
+        max: u64,
+    },
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenderError::Pipeline(e) => write!(f, "{}", e),
+            RenderError::JobLost => write!(f, "render job lost"),
+            RenderError::Cancelled => write!(f, "render cancelled"),
+            RenderError::TooLarge { size, max } => write!(
+                f,
+                "the animation weighs {} kB, over the limit of {} kB",
+                size / 1024,
+                max / 1024
+            ),
+        }
+    }
+}
+
 // impl std::error::Error for ServerError {}
@@ -0,0 +1,730 @@
+//! Formats of the exported videos, of their sound only, or of animated images.
+//!
+//! Options are checked against the encoders and muxers of the local GStreamer
+//! installation before anything is rendered.
+
+use serde::{Deserialize, Serialize};
+
+const MAX_FPS: u32 = 30;
+const MIN_WIDTH: u32 = 16;
+const MAX_WIDTH: u32 = 1280;
+/// In bytes
+const DEFAULT_MAX_ANIMATION_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Mp4,
+    Webm,
+    Mkv,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    Vp9,
+    Av1,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Aac,
+    Mp3,
+    Opus,
+    Vorbis,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    #[serde(rename = "source")]
+    Source,
+    #[serde(rename = "1080p")]
+    P1080,
+    #[serde(rename = "720p")]
+    P720,
+    #[serde(rename = "480p")]
+    P480,
+    #[serde(rename = "360p")]
+    P360,
+}
+
+/// Files of the audio-only exports, which have no video track at all
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Wav,
+    Mp3,
+    Ogg,
+    Flac,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationFormat {
+    Gif,
+    Webp,
+}
+
+/// Quantization of the GIF palette, the slower the closer to the source colors.
+/// WebP frames have no palette.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    Fast,
+    Balanced,
+    Accurate,
+}
+
+/// Silent animated image, for the chats which play them inline
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AnimationOptions {
+    pub format: AnimationFormat,
+    pub fps: u32,
+    /// In pixels, the height follows the aspect ratio
+    pub width: u32,
+    pub palette: Palette,
+    /// Burns the sentence of each segment into its frames
+    pub captions: bool,
+}
+
+impl Default for AnimationOptions {
+    fn default() -> Self {
+        AnimationOptions {
+            format: AnimationFormat::Gif,
+            fps: 15,
+            width: 480,
+            palette: Palette::Balanced,
+            captions: false,
+        }
+    }
+}
+
+/// Text burned into an animation, over the phonems of its segment
+#[derive(Debug, Clone, Hash)]
+pub struct Caption {
+    pub text: String,
+    /// Number of phonems of the segment
+    pub phonems: usize,
+}
+
+/// Bitrate presets, the encoders defaults being used without one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportOptions {
+    pub container: Container,
+    pub video_codec: VideoCodec,
+    pub audio_codec: AudioCodec,
+    pub resolution: Resolution,
+    pub quality: Option<Quality>,
+    /// Replaces the container and codecs of the video
+    pub audio_only: Option<AudioFormat>,
+    /// Replaces the container and codecs of the video, and drops the sound
+    pub animation: Option<AnimationOptions>,
+}
+
+impl Default for ExportOptions {
+    /// What exports were before they had options
+    fn default() -> Self {
+        ExportOptions {
+            container: Container::Mp4,
+            video_codec: VideoCodec::H264,
+            audio_codec: AudioCodec::Mp3,
+            resolution: Resolution::Source,
+            quality: None,
+            audio_only: None,
+            animation: None,
+        }
+    }
+}
+
+impl Container {
+    fn name(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Webm => "webm",
+            Container::Mkv => "mkv",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.name()
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Container::Mp4 => "video/mp4",
+            Container::Webm => "video/webm",
+            Container::Mkv => "video/x-matroska",
+        }
+    }
+
+    pub fn caps(self) -> gst::Caps {
+        match self {
+            Container::Mp4 => gst::Caps::new_simple("video/quicktime", &[]),
+            Container::Webm => gst::Caps::new_simple("video/webm", &[]),
+            Container::Mkv => gst::Caps::new_simple("video/x-matroska", &[]),
+        }
+    }
+
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4mux",
+            Container::Webm => "webmmux",
+            Container::Mkv => "matroskamux",
+        }
+    }
+
+    fn supports_video(self, codec: VideoCodec) -> bool {
+        !matches!(
+            (self, codec),
+            (Container::Mp4, VideoCodec::Vp9) | (Container::Webm, VideoCodec::H264)
+        )
+    }
+
+    fn supports_audio(self, codec: AudioCodec) -> bool {
+        !matches!(
+            (self, codec),
+            (Container::Mp4, AudioCodec::Vorbis)
+                | (Container::Webm, AudioCodec::Aac)
+                | (Container::Webm, AudioCodec::Mp3)
+        )
+    }
+}
+
+impl VideoCodec {
+    fn name(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 => "av1",
+        }
+    }
+
+    pub fn caps(self) -> gst::Caps {
+        match self {
+            VideoCodec::H264 => gst::Caps::new_simple("video/x-h264", &[]),
+            VideoCodec::Vp9 => gst::Caps::new_simple("video/x-vp9", &[]),
+            VideoCodec::Av1 => gst::Caps::new_simple("video/x-av1", &[]),
+        }
+    }
+
+    /// Encoders able to produce the codec, any of them will do
+    fn encoders(self) -> &'static [&'static str] {
+        match self {
+            VideoCodec::H264 => &["x264enc", "openh264enc"],
+            VideoCodec::Vp9 => &["vp9enc"],
+            VideoCodec::Av1 => &["av1enc", "rav1enc", "svtav1enc"],
+        }
+    }
+}
+
+impl AudioCodec {
+    fn name(self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::Opus => "opus",
+            AudioCodec::Vorbis => "vorbis",
+        }
+    }
+
+    pub fn caps(self) -> gst::Caps {
+        match self {
+            AudioCodec::Aac => gst::Caps::new_simple("audio/mpeg", &[("mpegversion", &4i32)]),
+            AudioCodec::Mp3 => {
+                gst::Caps::new_simple("audio/mpeg", &[("mpegversion", &1i32), ("layer", &3i32)])
+            }
+            AudioCodec::Opus => gst::Caps::new_simple("audio/x-opus", &[]),
+            AudioCodec::Vorbis => gst::Caps::new_simple("audio/x-vorbis", &[]),
+        }
+    }
+
+    fn encoders(self) -> &'static [&'static str] {
+        match self {
+            AudioCodec::Aac => &["avenc_aac", "fdkaacenc", "voaacenc"],
+            AudioCodec::Mp3 => &["lamemp3enc"],
+            AudioCodec::Opus => &["opusenc"],
+            AudioCodec::Vorbis => &["vorbisenc"],
+        }
+    }
+}
+
+impl AudioFormat {
+    fn name(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.name()
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Ogg => "audio/ogg",
+            AudioFormat::Flac => "audio/flac",
+        }
+    }
+
+    /// Caps of the audio stream
+    pub fn caps(self) -> gst::Caps {
+        match self {
+            AudioFormat::Wav => gst::Caps::new_simple("audio/x-raw", &[]),
+            AudioFormat::Mp3 => AudioCodec::Mp3.caps(),
+            AudioFormat::Ogg => AudioCodec::Opus.caps(),
+            AudioFormat::Flac => gst::Caps::new_simple("audio/x-flac", &[]),
+        }
+    }
+
+    /// Caps of the container, none for the streams written as they are
+    pub fn container_caps(self) -> Option<gst::Caps> {
+        match self {
+            AudioFormat::Wav => Some(gst::Caps::new_simple("audio/x-wav", &[])),
+            AudioFormat::Ogg => Some(gst::Caps::new_simple("application/ogg", &[])),
+            AudioFormat::Mp3 | AudioFormat::Flac => None,
+        }
+    }
+
+    /// Elements all needed to write the format
+    fn elements(self) -> &'static [&'static str] {
+        match self {
+            AudioFormat::Wav => &["wavenc"],
+            AudioFormat::Mp3 => &["lamemp3enc"],
+            AudioFormat::Ogg => &["opusenc", "oggmux"],
+            AudioFormat::Flac => &["flacenc"],
+        }
+    }
+}
+
+impl AnimationFormat {
+    fn name(self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Webp => "webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.name()
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "image/gif",
+            AnimationFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn caps(self) -> gst::Caps {
+        match self {
+            AnimationFormat::Gif => gst::Caps::new_simple("image/gif", &[]),
+            AnimationFormat::Webp => gst::Caps::new_simple("image/webp", &[]),
+        }
+    }
+
+    fn encoder(self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gifenc",
+            AnimationFormat::Webp => "webpenc",
+        }
+    }
+}
+
+impl Palette {
+    fn name(self) -> &'static str {
+        match self {
+            Palette::Fast => "fast",
+            Palette::Balanced => "balanced",
+            Palette::Accurate => "accurate",
+        }
+    }
+
+    /// `speed` of gifenc, from 1 (best colors) to 30
+    fn speed(self) -> u32 {
+        match self {
+            Palette::Fast => 30,
+            Palette::Balanced => 10,
+            Palette::Accurate => 1,
+        }
+    }
+}
+
+impl AnimationOptions {
+    /// Raw frames fed to the encoder
+    pub fn restriction(&self) -> gst::Caps {
+        gst::Caps::builder("video/x-raw")
+            .field("width", self.width as i32)
+            .field("framerate", gst::Fraction::new(self.fps as i32, 1))
+            .build()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.fps == 0 || self.fps > MAX_FPS {
+            return Err(format!("The frame rate must be between 1 and {}", MAX_FPS));
+        }
+        if self.width < MIN_WIDTH || self.width > MAX_WIDTH {
+            return Err(format!(
+                "The width must be between {} and {} pixels",
+                MIN_WIDTH, MAX_WIDTH
+            ));
+        }
+        if !is_installed(self.format.encoder()) {
+            return Err(format!("No {} encoder is installed", self.format.name()));
+        }
+        if self.captions && !is_installed("textoverlay") {
+            return Err("Captions cannot be drawn, textoverlay is not installed".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Largest animation that is sent, set with `ANIMATION_MAX_SIZE` in bytes
+pub fn max_animation_size() -> u64 {
+    std::env::var("ANIMATION_MAX_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ANIMATION_SIZE)
+}
+
+impl Resolution {
+    fn name(self) -> &'static str {
+        match self {
+            Resolution::Source => "source",
+            Resolution::P1080 => "1080p",
+            Resolution::P720 => "720p",
+            Resolution::P480 => "480p",
+            Resolution::P360 => "360p",
+        }
+    }
+
+    /// Height of the frames, the width follows the aspect ratio
+    pub fn height(self) -> Option<i32> {
+        match self {
+            Resolution::Source => None,
+            Resolution::P1080 => Some(1080),
+            Resolution::P720 => Some(720),
+            Resolution::P480 => Some(480),
+            Resolution::P360 => Some(360),
+        }
+    }
+}
+
+impl Quality {
+    fn name(self) -> &'static str {
+        match self {
+            Quality::Low => "low",
+            Quality::Medium => "medium",
+            Quality::High => "high",
+        }
+    }
+
+    /// In kbit/s
+    pub fn video_bitrate(self) -> u32 {
+        match self {
+            Quality::Low => 1000,
+            Quality::Medium => 2500,
+            Quality::High => 6000,
+        }
+    }
+
+    /// In kbit/s
+    pub fn audio_bitrate(self) -> u32 {
+        match self {
+            Quality::Low => 96,
+            Quality::Medium => 128,
+            Quality::High => 192,
+        }
+    }
+}
+
+/// Properties of an encoder giving it the bitrate of the preset, or making
+/// it write animations. Empty for the elements which are not known encoders.
+pub fn encoder_settings(encoder: &str, options: &ExportOptions) -> Vec<(&'static str, String)> {
+    match (encoder, &options.animation) {
+        ("gifenc", Some(animation)) => {
+            return vec![("speed", animation.palette.speed().to_string())]
+        }
+        // Without it, every frame would be a picture of its own
+        ("webpenc", Some(_)) => return vec![("animated", "true".to_owned())],
+        _ => (),
+    }
+    let quality = match options.quality {
+        Some(quality) => quality,
+        None => return vec![],
+    };
+    let video = quality.video_bitrate();
+    let audio = quality.audio_bitrate();
+    // Property, in kbit/s or bit/s
+    let (property, bitrate) = match encoder {
+        "x264enc" => ("bitrate", video),
+        "av1enc" | "svtav1enc" => ("target-bitrate", video),
+        "rav1enc" | "openh264enc" => ("bitrate", video * 1000),
+        "vp9enc" => ("target-bitrate", video * 1000),
+        "lamemp3enc" => {
+            return vec![
+                ("target", "bitrate".to_owned()),
+                ("bitrate", audio.to_string()),
+            ]
+        }
+        "avenc_aac" | "fdkaacenc" | "voaacenc" | "opusenc" | "vorbisenc" => {
+            ("bitrate", audio * 1000)
+        }
+        _ => return vec![],
+    };
+    vec![(property, bitrate.to_string())]
+}
+
+fn is_installed(element: &str) -> bool {
+    gst::ElementFactory::find(element).is_some()
+}
+
+impl ExportOptions {
+    /// Checks that the codecs fit in the container, and can be encoded here
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(animation) = &self.animation {
+            if self.audio_only.is_some() {
+                return Err("An animation cannot be audio-only".to_owned());
+            }
+            return animation.validate();
+        }
+        if let Some(format) = self.audio_only {
+            return match format.elements().iter().find(|e| !is_installed(e)) {
+                Some(missing) => Err(format!("{} is not installed", missing)),
+                None => Ok(()),
+            };
+        }
+        if !self.container.supports_video(self.video_codec) {
+            return Err(format!(
+                "{} cannot be stored in {}",
+                self.video_codec.name(),
+                self.container.name()
+            ));
+        }
+        if !self.container.supports_audio(self.audio_codec) {
+            return Err(format!(
+                "{} cannot be stored in {}",
+                self.audio_codec.name(),
+                self.container.name()
+            ));
+        }
+        if !is_installed(self.container.muxer()) {
+            return Err(format!("No {} muxer is installed", self.container.name()));
+        }
+        if !self.video_codec.encoders().iter().any(|e| is_installed(e)) {
+            return Err(format!(
+                "No {} encoder is installed",
+                self.video_codec.name()
+            ));
+        }
+        if !self.audio_codec.encoders().iter().any(|e| is_installed(e)) {
+            return Err(format!(
+                "No {} encoder is installed",
+                self.audio_codec.name()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match (self.audio_only, &self.animation) {
+            (Some(format), _) => format.extension(),
+            (None, Some(animation)) => animation.format.extension(),
+            (None, None) => self.container.extension(),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match (self.audio_only, &self.animation) {
+            (Some(format), _) => format.mime_type(),
+            (None, Some(animation)) => animation.format.mime_type(),
+            (None, None) => self.container.mime_type(),
+        }
+    }
+
+    /// Part of the name of the rendered file, empty for the default options so
+    /// that earlier renders are still found. Audio files are kept in a folder
+    /// of their own, only their bitrate sets them apart.
+    pub fn cache_key(&self) -> String {
+        if self.audio_only.is_some() {
+            return self.quality.map_or("", Quality::name).to_owned();
+        }
+        if let Some(animation) = &self.animation {
+            return format!(
+                "{}-{}fps-{}w-{}{}",
+                animation.format.name(),
+                animation.fps,
+                animation.width,
+                animation.palette.name(),
+                if animation.captions { "-captions" } else { "" }
+            );
+        }
+        if *self == ExportOptions::default() {
+            return String::new();
+        }
+        let quality = self.quality.map_or("default", Quality::name);
+        format!(
+            "{}-{}-{}-{}-{}",
+            self.container.name(),
+            self.video_codec.name(),
+            self.audio_codec.name(),
+            self.resolution.name(),
+            quality
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_quality(quality: Quality) -> ExportOptions {
+        ExportOptions {
+            quality: Some(quality),
+            ..ExportOptions::default()
+        }
+    }
+
+    #[test]
+    fn only_other_options_change_the_cache_key() {
+        assert_eq!(ExportOptions::default().cache_key(), "");
+        let webm = ExportOptions {
+            container: Container::Webm,
+            video_codec: VideoCodec::Vp9,
+            audio_codec: AudioCodec::Opus,
+            ..ExportOptions::default()
+        };
+        let small = ExportOptions {
+            resolution: Resolution::P360,
+            ..ExportOptions::default()
+        };
+        let keys = [
+            webm.cache_key(),
+            small.cache_key(),
+            with_quality(Quality::Low).cache_key(),
+            with_quality(Quality::High).cache_key(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!key.is_empty());
+            assert!(keys[i + 1..].iter().all(|other| other != key));
+        }
+    }
+
+    #[test]
+    fn bitrates_are_in_the_unit_of_the_encoder() {
+        let medium = with_quality(Quality::Medium);
+        let settings = |encoder| encoder_settings(encoder, &medium);
+        // kbit/s
+        assert_eq!(settings("x264enc"), vec![("bitrate", "2500".to_owned())]);
+        assert_eq!(
+            settings("svtav1enc"),
+            vec![("target-bitrate", "2500".to_owned())]
+        );
+        // bit/s
+        assert_eq!(
+            settings("openh264enc"),
+            vec![("bitrate", "2500000".to_owned())]
+        );
+        assert_eq!(
+            settings("vp9enc"),
+            vec![("target-bitrate", "2500000".to_owned())]
+        );
+        assert_eq!(settings("opusenc"), vec![("bitrate", "128000".to_owned())]);
+        assert_eq!(
+            settings("lamemp3enc"),
+            vec![
+                ("target", "bitrate".to_owned()),
+                ("bitrate", "128".to_owned())
+            ]
+        );
+        assert!(settings("matroskamux").is_empty());
+        // The encoders defaults
+        assert!(encoder_settings("x264enc", &ExportOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn animations_have_settings_of_their_own() {
+        let animation = |format, palette| ExportOptions {
+            quality: Some(Quality::High),
+            animation: Some(AnimationOptions {
+                format,
+                palette,
+                ..AnimationOptions::default()
+            }),
+            ..ExportOptions::default()
+        };
+        let speed = |palette| {
+            let settings = encoder_settings("gifenc", &animation(AnimationFormat::Gif, palette));
+            assert_eq!(settings.len(), 1);
+            assert_eq!(settings[0].0, "speed");
+            settings[0].1.parse::<u32>().unwrap()
+        };
+        // The slower the closer to the source colors
+        assert!(speed(Palette::Fast) > speed(Palette::Balanced));
+        assert!(speed(Palette::Balanced) > speed(Palette::Accurate));
+        assert_eq!(speed(Palette::Accurate), 1);
+        assert_eq!(
+            encoder_settings("webpenc", &animation(AnimationFormat::Webp, Palette::Fast)),
+            vec![("animated", "true".to_owned())]
+        );
+        // Not the encoder of an animation
+        assert!(encoder_settings("gifenc", &with_quality(Quality::High)).is_empty());
+    }
+
+    #[test]
+    fn animations_are_checked_before_rendering() {
+        let animation = |fps, width| ExportOptions {
+            animation: Some(AnimationOptions {
+                fps,
+                width,
+                ..AnimationOptions::default()
+            }),
+            ..ExportOptions::default()
+        };
+        assert!(animation(0, 480).validate().is_err());
+        assert!(animation(MAX_FPS + 1, 480).validate().is_err());
+        assert!(animation(15, MIN_WIDTH - 1).validate().is_err());
+        assert!(animation(15, MAX_WIDTH + 1).validate().is_err());
+        let audio = ExportOptions {
+            audio_only: Some(AudioFormat::Wav),
+            ..animation(15, 480)
+        };
+        assert!(audio.validate().is_err());
+    }
+
+    #[test]
+    fn codecs_must_fit_in_the_container() {
+        let options = |container, video_codec, audio_codec| ExportOptions {
+            container,
+            video_codec,
+            audio_codec,
+            ..ExportOptions::default()
+        };
+        let invalid = [
+            options(Container::Mp4, VideoCodec::Vp9, AudioCodec::Aac),
+            options(Container::Webm, VideoCodec::H264, AudioCodec::Opus),
+            options(Container::Webm, VideoCodec::Vp9, AudioCodec::Mp3),
+            options(Container::Mp4, VideoCodec::H264, AudioCodec::Vorbis),
+        ];
+        for options in invalid.iter() {
+            let error = options.validate().unwrap_err();
+            assert!(error.contains("cannot be stored in"), "{}", error);
+        }
+    }
+}
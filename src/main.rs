@@ -1,16 +1,25 @@
 use actix::*;
 use actix_files::{Files, NamedFile};
-use actix_web::{dev, get, middleware, App, HttpResponse, HttpServer, Responder};
+use actix_web::{dev, get, middleware, web, App, HttpResponse, HttpServer, Responder};
+use analyzer::Analyzer;
 use std::sync::Arc;
 
+mod analyzer;
+mod cache;
 mod data;
 mod downloader;
 mod error;
+mod export;
+mod markup;
 mod messages;
+mod normalize;
+mod render_pool;
 mod renderer;
 mod sm;
 mod sm_actor;
+mod sm_pool;
 mod socket;
+mod synonyms;
 mod youtube_dl;
 
 const FRONTEND_PATH: &str = "./front/dist/";
@@ -22,12 +31,12 @@ fn init() {
 }
 
 #[get("/test")]
-async fn test() -> impl Responder {
+async fn test(analyzer: web::Data<Arc<dyn Analyzer>>) -> impl Responder {
     let p = data::Project::new("lol", "4", &["_ZZ8oyZUGn8".to_string()]);
-    let res = sm::analyze(&p, "1 2").await;
+    let res = sm::analyze(&***analyzer, &p, &data::Segment::new("1 2")).await;
     match res {
         Ok(analysis_results) => {
-            let phs = &analysis_results[0];
+            let phs = &analysis_results.combos[0];
             let vid = crate::data::Video::from(crate::data::YoutubeId {
                 id: "_ZZ8oyZUGn8".to_owned(),
             });
@@ -35,7 +44,12 @@ async fn test() -> impl Responder {
                 Err(_) => HttpResponse::InternalServerError().finish(),
                 Ok(vid) => {
                     let vid = Arc::new(vid);
-                    let res = crate::renderer::preview(&[vid], phs);
+                    let res = crate::renderer::preview(
+                        vec![vid],
+                        phs.clone(),
+                        crate::render_pool::JobHandle::new(),
+                    )
+                    .await;
                     match res {
                         Ok(_) => HttpResponse::Ok().json(&*analysis_results),
                         Err(_) => HttpResponse::InternalServerError().finish(),
@@ -47,6 +61,21 @@ async fn test() -> impl Responder {
     }
 }
 
+#[get("/stats")]
+async fn stats(analyzer: web::Data<Arc<dyn Analyzer>>) -> impl Responder {
+    HttpResponse::Ok().json(sm::cache_stats(&***analyzer))
+}
+
+/// Uses the json fixture given by `SM_ANALYZER_FIXTURE` instead of sm-interface if set
+fn get_analyzer() -> Arc<dyn Analyzer> {
+    match std::env::var("SM_ANALYZER_FIXTURE") {
+        Ok(path) => Arc::new(
+            analyzer::MockAnalyzer::from_fixture(path.as_ref()).expect("Invalid analyzer fixture"),
+        ),
+        Err(_) => Arc::new(sm::SmInterface),
+    }
+}
+
 /// Run actix web server
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -56,13 +85,17 @@ async fn main() -> std::io::Result<()> {
         .parse()
         .expect("PORT must be a number");
     // Start chat server actor
-    let server = sm_actor::SmActor::new().start();
+    let analyzer = get_analyzer();
+    let server = sm_actor::SmActor::new(analyzer.clone()).start();
+    let cache = analyzer.clone();
 
-    HttpServer::new(move || {
+    let result = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Compress::default())
             .data(server.clone())
+            .data(analyzer.clone())
             .service(test)
+            .service(stats)
             .service(actix_web::web::resource("/ws/").to(socket::sm_route))
             .service(
                 Files::new("/", FRONTEND_PATH)
@@ -78,5 +111,7 @@ async fn main() -> std::io::Result<()> {
     })
     .bind(("0.0.0.0", port))?
     .run()
-    .await
+    .await;
+    sm::save_cache(&*cache);
+    result
 }
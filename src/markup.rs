@@ -0,0 +1,402 @@
+//! Markup of segment sentences:
+//!
+//! - `[pause 300ms]` or `[pause 1.5s]` inserts a silence
+//! - `{v2: some words}` takes the words from the second source video
+//! - `*word*` prefers the longest occurrence of the words
+//!
+//! Source videos are numbered from 1 in the markup, and from 0 everywhere else.
+
+use crate::data::{Combo, Phonem};
+use serde::Serialize;
+
+/// Longest pause accepted, in seconds
+const MAX_PAUSE: f64 = 10.0;
+
+/// Constraint on a word of the analyzed sentence
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct WordConstraint {
+    /// Index of the word in the analyzed sentence
+    pub word: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<u8>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub longest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pause {
+    /// Number of words of the analyzed sentence before the pause
+    pub after: usize,
+    /// In seconds
+    pub duration: f64,
+    /// Offsets of the pause markup in the sentence, in characters
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MarkupError {
+    pub message: String,
+    /// Offsets of the faulty markup in the sentence, in characters
+    pub start: usize,
+    pub end: usize,
+}
+
+impl MarkupError {
+    fn new(message: &str, start: usize, end: usize) -> Self {
+        MarkupError {
+            message: message.to_owned(),
+            start,
+            end,
+        }
+    }
+}
+
+enum Piece {
+    Text {
+        text: String,
+        /// Source video, and position of the markup choosing it
+        video: Option<(u8, usize)>,
+        longest: bool,
+    },
+    Pause {
+        duration: f64,
+        start: usize,
+        end: usize,
+    },
+}
+
+/// Duration of a `[...]` tag content, such as `pause 300ms`
+fn parse_pause(tag: &str) -> Option<f64> {
+    let duration = tag.strip_prefix("pause")?.trim();
+    let (value, scale) = if let Some(ms) = duration.strip_suffix("ms") {
+        (ms, 0.001)
+    } else {
+        (duration.strip_suffix('s')?, 1.0)
+    };
+    let seconds = value.trim().parse::<f64>().ok()? * scale;
+    if seconds > 0.0 && seconds <= MAX_PAUSE {
+        Some(seconds)
+    } else {
+        None
+    }
+}
+
+/// Source video of a `{vN:` opening, `N` being counted from 1
+fn parse_video(tag: &str) -> Option<u8> {
+    let n: u8 = tag.trim().strip_prefix('v')?.parse().ok()?;
+    n.checked_sub(1)
+}
+
+fn parse(sentence: &str) -> Result<Vec<Piece>, MarkupError> {
+    let chars: Vec<char> = sentence.chars().collect();
+    let mut pieces = vec![];
+    let mut text = String::new();
+    // Position of the opening of the current `{...}` and `*...*`
+    let mut video: Option<(u8, usize)> = None;
+    let mut longest: Option<usize> = None;
+
+    macro_rules! flush {
+        () => {
+            if !text.is_empty() {
+                pieces.push(Piece::Text {
+                    text: std::mem::take(&mut text),
+                    video,
+                    longest: longest.is_some(),
+                });
+            }
+        };
+    }
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '[' => {
+                let end = (i..chars.len())
+                    .find(|&j| chars[j] == ']')
+                    .ok_or_else(|| MarkupError::new("Unclosed [", i, chars.len()))?;
+                let tag: String = chars[i + 1..end].iter().collect();
+                let duration = parse_pause(tag.trim()).ok_or_else(|| {
+                    MarkupError::new(
+                        "Expected [pause <duration>ms] or [pause <duration>s]",
+                        i,
+                        end + 1,
+                    )
+                })?;
+                flush!();
+                pieces.push(Piece::Pause {
+                    duration,
+                    start: i,
+                    end: end + 1,
+                });
+                i = end;
+            }
+            '{' => {
+                if let Some((_, start)) = video {
+                    return Err(MarkupError::new("Nested {", start, i + 1));
+                }
+                let colon = (i..chars.len())
+                    .take_while(|&j| chars[j] != '}')
+                    .find(|&j| chars[j] == ':')
+                    .ok_or_else(|| MarkupError::new("Expected {v<video>: words}", i, i + 1))?;
+                let tag: String = chars[i + 1..colon].iter().collect();
+                let v = parse_video(&tag)
+                    .ok_or_else(|| MarkupError::new("Videos are numbered from v1", i, colon + 1))?;
+                flush!();
+                video = Some((v, i));
+                i = colon;
+            }
+            '}' => {
+                if video.is_none() {
+                    return Err(MarkupError::new("Unexpected }", i, i + 1));
+                }
+                flush!();
+                video = None;
+            }
+            ']' => return Err(MarkupError::new("Unexpected ]", i, i + 1)),
+            '*' => {
+                flush!();
+                longest = match longest {
+                    Some(_) => None,
+                    None => Some(i),
+                };
+            }
+            c => text.push(c),
+        }
+        i += 1;
+    }
+
+    if let Some((_, start)) = video {
+        return Err(MarkupError::new("Unclosed {", start, chars.len()));
+    }
+    if let Some(start) = longest {
+        return Err(MarkupError::new("Unclosed *", start, chars.len()));
+    }
+    flush!();
+    Ok(pieces)
+}
+
+/// Sentence as given to the analyzer, with the constraints of its markup
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzedSentence {
+    pub text: String,
+    pub constraints: Vec<WordConstraint>,
+    pub pauses: Vec<Pause>,
+    /// Number of words of `text`
+    pub word_count: usize,
+}
+
+/// Parses the markup of the sentence and normalizes its text, unless `raw`.
+/// Source videos are checked if `video_count` is given.
+pub fn analyze(
+    sentence: &str,
+    raw: bool,
+    language: Option<&str>,
+    video_count: Option<usize>,
+) -> Result<AnalyzedSentence, MarkupError> {
+    let mut analyzed = AnalyzedSentence::default();
+    let mut texts = vec![];
+    let mut word_count = 0;
+    for piece in parse(sentence)? {
+        match piece {
+            Piece::Text {
+                text,
+                video,
+                longest,
+            } => {
+                if let (Some((v, start)), Some(count)) = (video, video_count) {
+                    if v as usize >= count {
+                        return Err(MarkupError::new(
+                            &format!("The project has {} source videos", count),
+                            start,
+                            start + 1,
+                        ));
+                    }
+                }
+                let video = video.map(|(v, _)| v);
+                let text = if raw {
+                    text
+                } else {
+                    crate::normalize::normalize(&text, language)
+                };
+                let count = crate::analyzer::words(&text).len();
+                if video.is_some() || longest {
+                    analyzed
+                        .constraints
+                        .extend((word_count..word_count + count).map(|word| WordConstraint {
+                            word,
+                            video,
+                            longest,
+                        }));
+                }
+                word_count += count;
+                texts.push(text);
+            }
+            Piece::Pause {
+                duration,
+                start,
+                end,
+            } => analyzed.pauses.push(Pause {
+                after: word_count,
+                duration,
+                start,
+                end,
+            }),
+        }
+    }
+    analyzed.word_count = word_count;
+    analyzed.text = texts
+        .iter()
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(analyzed)
+}
+
+/// Inserts silent gaps in the combo. Pauses are placed using the word index
+/// of the phonems, phonems without one belong to the same word as the
+/// previous phonem.
+///
+/// sm-interface does not always give word indices: without them only the
+/// pauses at the start and at the end of the sentence can be placed, the
+/// others are rejected.
+pub fn insert_pauses(combo: Combo, analyzed: &AnalyzedSentence) -> Result<Combo, MarkupError> {
+    if combo.iter().all(|p| p.word.is_none()) {
+        if let Some(pause) = analyzed
+            .pauses
+            .iter()
+            .find(|p| p.after > 0 && p.after < analyzed.word_count)
+        {
+            return Err(MarkupError::new(
+                "The words of this sentence cannot be located, pauses are only possible at its start or end",
+                pause.start,
+                pause.end,
+            ));
+        }
+    }
+    let mut pauses = analyzed.pauses.iter().peekable();
+    let mut word = 0;
+    let mut timeline = Vec::with_capacity(combo.len() + pauses.len());
+    for phonem in combo {
+        word = phonem.word.unwrap_or(word);
+        while let Some(pause) = pauses.next_if(|p| p.after <= word) {
+            timeline.push(Phonem::gap(pause.duration));
+        }
+        timeline.push(phonem);
+    }
+    timeline.extend(pauses.map(|p| Phonem::gap(p.duration)));
+    Ok(timeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phonem(word: Option<usize>) -> Phonem {
+        Phonem {
+            video_index: 0,
+            start: 0.0,
+            end: 0.1,
+            label: None,
+            word,
+            gap: false,
+        }
+    }
+
+    fn gaps(combo: &[Phonem]) -> Vec<usize> {
+        combo
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.gap)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn pauses_are_counted_in_words() {
+        let analyzed = analyze(
+            "hello [pause 300ms] big world[pause 1.5s]",
+            true,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(analyzed.text, "hello big world");
+        assert_eq!(analyzed.word_count, 3);
+        assert_eq!(
+            analyzed.pauses,
+            vec![
+                Pause {
+                    after: 1,
+                    duration: 0.3,
+                    start: 6,
+                    end: 19,
+                },
+                Pause {
+                    after: 3,
+                    duration: 1.5,
+                    start: 29,
+                    end: 41,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn constraints_apply_to_the_marked_words() {
+        let analyzed = analyze("say {v2: hello *big*} world", true, None, Some(2)).unwrap();
+        assert_eq!(analyzed.text, "say hello big world");
+        assert_eq!(
+            analyzed.constraints,
+            vec![
+                WordConstraint {
+                    word: 1,
+                    video: Some(1),
+                    longest: false,
+                },
+                WordConstraint {
+                    word: 2,
+                    video: Some(1),
+                    longest: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_markup_is_located() {
+        let error = |s: &str| {
+            let e = analyze(s, true, None, Some(2)).unwrap_err();
+            (e.start, e.end)
+        };
+        assert_eq!(error("hello [pause 11s]"), (6, 17));
+        assert_eq!(error("hello [pause]"), (6, 13));
+        assert_eq!(error("{v0: hello}"), (0, 4));
+        assert_eq!(error("{v3: hello}"), (0, 1));
+        assert_eq!(error("{v1: {v2: hello}}"), (0, 6));
+        assert_eq!(error("hello *world"), (6, 12));
+        assert_eq!(error("hello }"), (6, 7));
+    }
+
+    #[test]
+    fn pauses_follow_the_word_indices() {
+        let analyzed = analyze("one [pause 1s] two [pause 2s]", true, None, None).unwrap();
+        let combo = vec![phonem(Some(0)), phonem(None), phonem(Some(1))];
+        let combo = insert_pauses(combo, &analyzed).unwrap();
+        assert_eq!(gaps(&combo), vec![2, 4]);
+        assert_eq!(combo[4].end, 2.0);
+    }
+
+    #[test]
+    fn pauses_without_word_indices_only_at_the_ends() {
+        let combo = vec![phonem(None), phonem(None)];
+        let analyzed = analyze("[pause 1s] one two [pause 2s]", true, None, None).unwrap();
+        let with_pauses = insert_pauses(combo.clone(), &analyzed).unwrap();
+        assert_eq!(gaps(&with_pauses), vec![0, 3]);
+
+        let analyzed = analyze("one [pause 1s] two", true, None, None).unwrap();
+        let error = insert_pauses(combo, &analyzed).unwrap_err();
+        assert_eq!((error.start, error.end), (4, 14));
+    }
+}
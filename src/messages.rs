@@ -1,5 +1,11 @@
-use crate::data::{Preview, Project, ProjectId, Seed, Segment, YoutubeId};
+use crate::data::{
+    ComboInfo, Phonem, Preview, Project, ProjectId, Reading, SearchHit, SearchQuery, Seed, Segment,
+    VocabularyEntry, WordCoverage, WordDiagnostic, YoutubeId,
+};
+use crate::markup::MarkupError;
+use crate::render_pool::JobId;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 use crate::sm_actor;
 
@@ -10,10 +16,25 @@ pub enum ClientRequest {
     DeleteProject(sm_actor::DeleteProject),
     JoinProject(sm_actor::JoinProject),
     CreateSegment(sm_actor::CreateSegment),
+    CheckCoverage(sm_actor::CheckCoverage),
+    ListVocabulary(sm_actor::ListVocabulary),
     ModifySegmentSentence(sm_actor::ModifySegmentSentence),
     ModifySegmentComboIndex(sm_actor::ModifySegmentComboIndex),
     RemoveSegment(sm_actor::RemoveSegment),
+    ResolveAmbiguity(sm_actor::ResolveAmbiguity),
+    SetPronunciation(sm_actor::SetPronunciation),
+    ListCombos(sm_actor::ListCombos),
+    ListPhonemAlternatives(sm_actor::ListPhonemAlternatives),
+    OverridePhonem(sm_actor::OverridePhonem),
+    RerollSegment(sm_actor::RerollSegment),
+    FreezeSegments(sm_actor::FreezeSegments),
+    UnfreezeSegments(sm_actor::UnfreezeSegments),
+    SetNormalization(sm_actor::SetNormalization),
+    SetLanguage(sm_actor::SetLanguage),
+    ListLanguages(sm_actor::ListLanguages),
+    Search(sm_actor::Search),
     Export(sm_actor::Export),
+    CancelRender(sm_actor::CancelRender),
 }
 
 #[derive(Serialize)]
@@ -42,6 +63,15 @@ pub enum ServerRequest {
         video_urls: Vec<YoutubeId>,
         name: ProjectId,
         segments: Vec<Segment>,
+        dictionary: BTreeMap<String, Vec<String>>,
+        language: Option<String>,
+    },
+    ChangeLanguage {
+        language: Option<String>,
+    },
+    /// Languages supported by the analyzer, empty if it cannot tell
+    Languages {
+        languages: Vec<String>,
     },
     #[serde(rename_all = "camelCase")]
     ChangeProjectName {
@@ -70,15 +100,130 @@ pub enum ServerRequest {
         row: usize,
         sentence: String,
     },
+    ChangeSegmentSeed {
+        row: usize,
+        seed: Seed,
+    },
+    ChangeNormalization {
+        row: usize,
+        enabled: bool,
+    },
+    /// The segments at `rows` have been frozen, or unfrozen. `skipped` are
+    /// the rows which could not be frozen, not having been analyzed.
+    ChangeFrozen {
+        rows: Vec<usize>,
+        frozen: bool,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        skipped: Vec<usize>,
+    },
     ChangeListProjects {
         projects: Vec<Project>,
     },
+    /// `skipped` are the rows left out of an export, which could not be
+    /// analyzed or rendered
+    #[serde(rename_all = "camelCase")]
     RenderResult {
         hash: String,
         data: String,
+        mime_type: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        skipped: Vec<usize>,
+    },
+    /// A preview of the segment `row`, or the export `hash`, is being rendered
+    RenderStarted {
+        id: JobId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        row: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+    },
+    RenderCancelled {
+        id: JobId,
+    },
+    /// Progress of the export `hash`, `eta` being the estimated remaining seconds
+    RenderProgress {
+        hash: String,
+        percent: u8,
+        eta: Option<f64>,
+    },
+    RenderFailed {
+        hash: String,
+        error: String,
+    },
+    /// The analyzer did not answer in time, editing the segment tries again
+    AnalysisTimeout {
+        row: usize,
     },
     AmbiguityToken {
         row: usize,
         token: String,
+        candidates: Vec<Reading>,
+    },
+    /// Status of every word of a segment, `sentence` being the analyzed form
+    /// of the sentence the word offsets refer to
+    SentenceDiagnostics {
+        row: usize,
+        sentence: String,
+        words: Vec<WordDiagnostic>,
+    },
+    /// The sentence of a segment cannot be analyzed until its markup is fixed
+    MarkupError {
+        row: usize,
+        error: MarkupError,
+    },
+    /// Which words of a text the sources can say, `text` being the analyzed
+    /// form the word offsets refer to
+    Coverage {
+        text: String,
+        words: Vec<WordCoverage>,
+    },
+    /// Words and phonems the sources of the project can say, the most frequent first
+    Vocabulary {
+        words: Vec<VocabularyEntry>,
+        phonems: Vec<VocabularyEntry>,
+    },
+    /// A page of the combos of a segment
+    Combos {
+        row: usize,
+        total: usize,
+        offset: usize,
+        combos: Vec<ComboInfo>,
+    },
+    ChangePronunciation {
+        word: String,
+        phonems: Option<Vec<String>>,
+    },
+    ChangeReading {
+        row: usize,
+        word: String,
+        reading: Option<String>,
+    },
+    /// Occurrences which can replace a phonem of the chosen combo of a segment
+    #[serde(rename_all = "camelCase")]
+    PhonemAlternatives {
+        row: usize,
+        phonem_index: usize,
+        original: Phonem,
+        alternatives: Vec<Phonem>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChangePhonemOverride {
+        row: usize,
+        phonem_index: usize,
+        phonem: Option<Phonem>,
+    },
+    /// Occurrences found for a search, `total` counting the ones not sent.
+    /// The clips of the hits are rendered by the job `id`.
+    SearchResults {
+        id: JobId,
+        query: SearchQuery,
+        total: usize,
+        hits: Vec<SearchHit>,
+    },
+    /// Low resolution clip of the hit `index` of a search
+    SearchClip {
+        query: SearchQuery,
+        index: usize,
+        data: String,
     },
 }
@@ -0,0 +1,640 @@
+//! Spelling out of what sm-interface cannot pronounce: numbers, dates,
+//! currencies, symbols and common abbreviations.
+//!
+//! English is used for unknown languages.
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Lang {
+    En,
+    Fr,
+}
+
+impl Lang {
+    pub(crate) fn from_code(code: Option<&str>) -> Self {
+        match code {
+            Some(c) if c.to_lowercase().starts_with("fr") => Lang::Fr,
+            _ => Lang::En,
+        }
+    }
+}
+
+const EN_ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const EN_TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const EN_SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+const EN_MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+const FR_ONES: [&str; 17] = [
+    "zéro", "un", "deux", "trois", "quatre", "cinq", "six", "sept", "huit", "neuf", "dix", "onze",
+    "douze", "treize", "quatorze", "quinze", "seize",
+];
+const FR_TENS: [&str; 7] = [
+    "",
+    "dix",
+    "vingt",
+    "trente",
+    "quarante",
+    "cinquante",
+    "soixante",
+];
+const FR_MONTHS: [&str; 12] = [
+    "janvier",
+    "février",
+    "mars",
+    "avril",
+    "mai",
+    "juin",
+    "juillet",
+    "août",
+    "septembre",
+    "octobre",
+    "novembre",
+    "décembre",
+];
+
+fn en_below_thousand(n: u64, words: &mut Vec<&'static str>) {
+    if n >= 100 {
+        words.push(EN_ONES[(n / 100) as usize]);
+        words.push("hundred");
+    }
+    let n = n % 100;
+    if n >= 20 {
+        words.push(EN_TENS[(n / 10) as usize]);
+        match n % 10 {
+            0 => {}
+            u => words.push(EN_ONES[u as usize]),
+        }
+    } else if n > 0 {
+        words.push(EN_ONES[n as usize]);
+    }
+}
+
+fn en_cardinal(n: u64) -> String {
+    if n == 0 {
+        return EN_ONES[0].to_owned();
+    }
+    let mut words = vec![];
+    let mut rest = n;
+    for (scale, name) in EN_SCALES.iter() {
+        if rest >= *scale {
+            en_below_thousand(rest / scale, &mut words);
+            words.push(name);
+            rest %= scale;
+        }
+    }
+    en_below_thousand(rest, &mut words);
+    words.join(" ")
+}
+
+fn fr_below_hundred(n: u64, words: &mut Vec<&'static str>) {
+    match n {
+        0 => {}
+        1..=16 => words.push(FR_ONES[n as usize]),
+        17..=19 => {
+            words.push("dix");
+            words.push(FR_ONES[(n - 10) as usize]);
+        }
+        20..=69 => {
+            words.push(FR_TENS[(n / 10) as usize]);
+            match n % 10 {
+                0 => {}
+                1 => {
+                    words.push("et");
+                    words.push("un");
+                }
+                u => words.push(FR_ONES[u as usize]),
+            }
+        }
+        70..=79 => {
+            words.push("soixante");
+            if n == 71 {
+                words.push("et");
+            }
+            fr_below_hundred(n - 60, words);
+        }
+        80 => {
+            words.push("quatre");
+            words.push("vingts");
+        }
+        _ => {
+            words.push("quatre");
+            words.push("vingt");
+            fr_below_hundred(n - 80, words);
+        }
+    }
+}
+
+fn fr_below_thousand(n: u64, words: &mut Vec<&'static str>) {
+    let (hundreds, rest) = (n / 100, n % 100);
+    if hundreds > 1 {
+        words.push(FR_ONES[hundreds as usize]);
+    }
+    if hundreds > 0 {
+        words.push(if hundreds > 1 && rest == 0 {
+            "cents"
+        } else {
+            "cent"
+        });
+    }
+    fr_below_hundred(rest, words);
+}
+
+fn fr_cardinal(n: u64) -> String {
+    if n == 0 {
+        return FR_ONES[0].to_owned();
+    }
+    let mut words = vec![];
+    let mut rest = n;
+    for (scale, singular, plural) in [
+        (1_000_000_000_000, "billion", "billions"),
+        (1_000_000_000, "milliard", "milliards"),
+        (1_000_000, "million", "millions"),
+    ]
+    .iter()
+    {
+        if rest >= *scale {
+            let count = rest / scale;
+            fr_below_thousand(count, &mut words);
+            words.push(if count > 1 { plural } else { singular });
+            rest %= scale;
+        }
+    }
+    if rest >= 1000 {
+        let count = rest / 1000;
+        // "mille", not "un mille", and "mille" never takes an s
+        if count > 1 {
+            fr_below_thousand(count, &mut words);
+        }
+        words.push("mille");
+        rest %= 1000;
+    }
+    fr_below_thousand(rest, &mut words);
+    // "quatre vingts" and "cents" lose their s when followed by a number
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, w)| match (*w, words.get(i + 1)) {
+            ("vingts", Some(next)) if !next.ends_with('s') => "vingt",
+            ("cents", Some(next)) if !next.ends_with('s') => "cent",
+            (w, _) => w,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn cardinal(n: u64, lang: Lang) -> String {
+    match lang {
+        Lang::En => en_cardinal(n),
+        Lang::Fr => fr_cardinal(n),
+    }
+}
+
+/// Spells digits one by one, for numbers too long to be read
+fn digits(s: &str, lang: Lang) -> String {
+    s.chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|d| cardinal(d as u64, lang))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn integer(s: &str, lang: Lang) -> String {
+    let plain: String = s.chars().filter(|c| c.is_ascii_digit()).collect();
+    match plain.parse::<u64>() {
+        Ok(n) if n < 1_000_000_000_000_000 => cardinal(n, lang),
+        _ => digits(&plain, lang),
+    }
+}
+
+fn ordinal(n: u64, lang: Lang, feminine: bool) -> String {
+    let cardinal = cardinal(n, lang);
+    let (head, last) = match cardinal.rfind(' ') {
+        Some(i) => (&cardinal[..=i], &cardinal[i + 1..]),
+        None => ("", &cardinal[..]),
+    };
+    let last = match lang {
+        Lang::En => match last {
+            "one" => "first".to_owned(),
+            "two" => "second".to_owned(),
+            "three" => "third".to_owned(),
+            "five" => "fifth".to_owned(),
+            "eight" => "eighth".to_owned(),
+            "nine" => "ninth".to_owned(),
+            "twelve" => "twelfth".to_owned(),
+            w if w.ends_with('y') => format!("{}ieth", &w[..w.len() - 1]),
+            w => format!("{}th", w),
+        },
+        Lang::Fr if n == 1 => (if feminine { "première" } else { "premier" }).to_owned(),
+        Lang::Fr => match last {
+            "un" => "unième".to_owned(),
+            "cinq" => "cinquième".to_owned(),
+            "neuf" => "neuvième".to_owned(),
+            "cents" | "vingts" => format!("{}ième", &last[..last.len() - 1]),
+            w if w.ends_with('e') => format!("{}ième", &w[..w.len() - 1]),
+            w => format!("{}ième", w),
+        },
+    };
+    format!("{}{}", head, last)
+}
+
+fn year(n: u64, lang: Lang) -> String {
+    match lang {
+        // Years are read by pairs of digits: "nineteen ninety nine"
+        Lang::En if (1100..2000).contains(&n) || (2010..2100).contains(&n) => {
+            let (high, low) = (n / 100, n % 100);
+            match low {
+                0 => format!("{} hundred", en_cardinal(high)),
+                1..=9 => format!("{} oh {}", en_cardinal(high), en_cardinal(low)),
+                _ => format!("{} {}", en_cardinal(high), en_cardinal(low)),
+            }
+        }
+        _ => cardinal(n, lang),
+    }
+}
+
+fn days_in_month(month: u64, year: u64) -> u64 {
+    match month {
+        2 => match (year % 4, year % 100, year % 400) {
+            (_, _, 0) | (0, 1..=99, _) => 29,
+            _ => 28,
+        },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn date(day: u64, month: u64, y: u64, lang: Lang) -> Option<String> {
+    if !(1..=12).contains(&month) || !(1..=days_in_month(month, y)).contains(&day) {
+        return None;
+    }
+    let month = month as usize - 1;
+    Some(match lang {
+        Lang::En => format!(
+            " {} {} {} ",
+            EN_MONTHS[month],
+            ordinal(day, lang, false),
+            year(y, lang)
+        ),
+        Lang::Fr => {
+            let day = if day == 1 {
+                ordinal(day, lang, false)
+            } else {
+                cardinal(day, lang)
+            };
+            format!(" {} {} {} ", day, FR_MONTHS[month], year(y, lang))
+        }
+    })
+}
+
+/// Integer part and decimal part of a number written in the language
+fn split_number(s: &str, lang: Lang) -> (&str, Option<&str>) {
+    // French groups its thousands with a dot, "1.000" is an integer
+    let separator = match lang {
+        Lang::En => '.',
+        Lang::Fr => ',',
+    };
+    match s.find(separator) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    }
+}
+
+fn number(s: &str, lang: Lang) -> String {
+    let (int, decimals) = split_number(s, lang);
+    let int = integer(int, lang);
+    match (decimals, lang) {
+        (None, _) => int,
+        (Some(d), Lang::En) => format!("{} point {}", int, digits(d, lang)),
+        (Some(d), Lang::Fr) => format!("{} virgule {}", int, integer(d, lang)),
+    }
+}
+
+fn currency(amount: &str, symbol: &str, lang: Lang) -> String {
+    let (singular, plural, cent, cents) = match (symbol, lang) {
+        ("$", _) => ("dollar", "dollars", "cent", "cents"),
+        ("€", Lang::En) => ("euro", "euros", "cent", "cents"),
+        ("€", Lang::Fr) => ("euro", "euros", "centime", "centimes"),
+        (_, Lang::En) => ("pound", "pounds", "penny", "pence"),
+        (_, Lang::Fr) => ("livre", "livres", "centime", "centimes"),
+    };
+    let (int, decimals) = split_number(amount, lang);
+    let units: u64 = int
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or(0);
+    let mut spoken = format!(
+        " {} {}",
+        integer(int, lang),
+        if units > 1 { plural } else { singular }
+    );
+    if let Some(d) = decimals {
+        // "5.5" means 50 cents
+        let d: u64 = format!("{:0<2}", d).parse().unwrap_or(0);
+        if d > 0 {
+            let and = if lang == Lang::En { "and" } else { "et" };
+            let unit = if d > 1 { cents } else { cent };
+            spoken = format!("{} {} {} {}", spoken, and, cardinal(d, lang), unit);
+        }
+    }
+    spoken + " "
+}
+
+const EN_ABBREVIATIONS: [(&str, &str); 11] = [
+    ("mr.", "mister"),
+    ("mrs.", "missus"),
+    ("ms.", "miss"),
+    ("dr.", "doctor"),
+    ("st.", "saint"),
+    ("jr.", "junior"),
+    ("vs.", "versus"),
+    ("vs", "versus"),
+    ("etc.", "et cetera"),
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+];
+const FR_ABBREVIATIONS: [(&str, &str); 10] = [
+    ("m.", "monsieur"),
+    ("mme", "madame"),
+    ("mlle", "mademoiselle"),
+    ("dr", "docteur"),
+    ("st", "saint"),
+    ("ste", "sainte"),
+    ("etc.", "et cetera"),
+    ("n°", "numéro"),
+    ("p.ex.", "par exemple"),
+    ("cf.", "confer"),
+];
+
+fn abbreviations(sentence: &str, lang: Lang) -> String {
+    let table: &[(&str, &str)] = match lang {
+        Lang::En => &EN_ABBREVIATIONS,
+        Lang::Fr => &FR_ABBREVIATIONS,
+    };
+    sentence
+        .split(' ')
+        .map(|token| {
+            let core = token.trim_end_matches(|c| ",;:!?".contains(c));
+            let lower = core.to_lowercase();
+            match table.iter().find(|(a, _)| *a == lower) {
+                Some((_, expansion)) => format!("{}{}", expansion, &token[core.len()..]),
+                None => token.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn symbol(c: char, lang: Lang) -> Option<&'static str> {
+    Some(match (c, lang) {
+        ('&', Lang::En) => "and",
+        ('&', Lang::Fr) => "et",
+        ('+', _) => "plus",
+        ('=', Lang::En) => "equals",
+        ('=', Lang::Fr) => "égale",
+        ('@', Lang::En) => "at",
+        ('@', Lang::Fr) => "arobase",
+        ('%', Lang::En) => "percent",
+        ('%', Lang::Fr) => "pour cent",
+        ('°', Lang::En) => "degrees",
+        ('°', Lang::Fr) => "degrés",
+        _ => return None,
+    })
+}
+
+/// Characters kept as they are, the others are spelled out or removed
+fn is_plain(c: char) -> bool {
+    c.is_alphanumeric() || c.is_whitespace() || "'’-.,!?;:".contains(c)
+}
+
+static ISO_DATE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b([0-9]{4})-([0-9]{1,2})-([0-9]{1,2})\b").unwrap());
+static DATE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b([0-9]{1,2})/([0-9]{1,2})/([0-9]{4})\b").unwrap());
+// Digits are matched as `[0-9]`, `\d` would match other scripts which cannot be parsed
+const EN_NUMBER: &str = r"[0-9]{1,3}(?:,[0-9]{3})+(?:\.[0-9]+)?|[0-9]+(?:\.[0-9]+)?";
+const FR_NUMBER: &str = r"[0-9]{1,3}(?:[  .][0-9]{3})+(?:,[0-9]+)?|[0-9]+(?:,[0-9]+)?";
+
+struct Patterns {
+    currency_before: Regex,
+    currency_after: Regex,
+    percent: Regex,
+    ordinal: Regex,
+    number: Regex,
+}
+
+fn patterns(number: &str, ordinal: &str) -> Patterns {
+    Patterns {
+        currency_before: Regex::new(&format!(r"([$€£])\s?({})", number)).unwrap(),
+        currency_after: Regex::new(&format!(r"({})\s?([$€£])", number)).unwrap(),
+        percent: Regex::new(&format!(r"({})\s?%", number)).unwrap(),
+        ordinal: Regex::new(ordinal).unwrap(),
+        number: Regex::new(number).unwrap(),
+    }
+}
+
+static EN_PATTERNS: Lazy<Patterns> =
+    Lazy::new(|| patterns(EN_NUMBER, r"\b([0-9]+)(?:st|nd|rd|th)\b"));
+static FR_PATTERNS: Lazy<Patterns> =
+    Lazy::new(|| patterns(FR_NUMBER, r"\b([0-9]+)(er|re|ère|ème|eme|e)\b"));
+static SPACE_BEFORE_PUNCTUATION: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+([,.!?;:])").unwrap());
+
+/// Rewrites the sentence with words only, for the language code `language`
+pub fn normalize(sentence: &str, language: Option<&str>) -> String {
+    let lang = Lang::from_code(language);
+    let p = match lang {
+        Lang::En => &*EN_PATTERNS,
+        Lang::Fr => &*FR_PATTERNS,
+    };
+
+    let s = abbreviations(sentence, lang);
+    let s = ISO_DATE.replace_all(&s, |c: &Captures| {
+        let spoken = || {
+            date(
+                c[3].parse().ok()?,
+                c[2].parse().ok()?,
+                c[1].parse().ok()?,
+                lang,
+            )
+        };
+        spoken().unwrap_or_else(|| c[0].to_owned())
+    });
+    let s = DATE.replace_all(&s, |c: &Captures| {
+        // 03/04/2020 is the 4th of March in English, the 3rd of April in French
+        let (day, month) = match lang {
+            Lang::En => (&c[2], &c[1]),
+            Lang::Fr => (&c[1], &c[2]),
+        };
+        let spoken = || {
+            date(
+                day.parse().ok()?,
+                month.parse().ok()?,
+                c[3].parse().ok()?,
+                lang,
+            )
+        };
+        spoken().unwrap_or_else(|| c[0].to_owned())
+    });
+    let s = p
+        .currency_before
+        .replace_all(&s, |c: &Captures| currency(&c[2], &c[1], lang));
+    let s = p
+        .currency_after
+        .replace_all(&s, |c: &Captures| currency(&c[1], &c[2], lang));
+    let s = p.percent.replace_all(&s, |c: &Captures| {
+        format!(" {} {} ", number(&c[1], lang), symbol('%', lang).unwrap())
+    });
+    let s = p.ordinal.replace_all(&s, |c: &Captures| {
+        // The regex only matches digits
+        let n = c[1].parse().unwrap_or(0);
+        let feminine = c.get(2).map(|m| m.as_str()) == Some("re");
+        format!(" {} ", ordinal(n, lang, feminine))
+    });
+    let s = p
+        .number
+        .replace_all(&s, |c: &Captures| format!(" {} ", number(&c[0], lang)));
+
+    let s: String = s
+        .chars()
+        .map(|c| match symbol(c, lang) {
+            Some(word) => format!(" {} ", word),
+            None if is_plain(c) => c.to_string(),
+            None => " ".to_owned(),
+        })
+        .collect();
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    SPACE_BEFORE_PUNCTUATION.replace_all(&s, "$1").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn en(s: &str) -> String {
+        normalize(s, Some("en"))
+    }
+
+    fn fr(s: &str) -> String {
+        normalize(s, Some("fr-FR"))
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(en("21 cats"), "twenty one cats");
+        assert_eq!(
+            en("1,234,567"),
+            "one million two hundred thirty four thousand five hundred sixty seven"
+        );
+        assert_eq!(en("pi is 3.14"), "pi is three point one four");
+        assert_eq!(
+            fr("71 chiens et 80 oiseaux"),
+            "soixante et onze chiens et quatre vingts oiseaux"
+        );
+        assert_eq!(fr("1 280"), "mille deux cent quatre vingts");
+        assert_eq!(fr("281"), "deux cent quatre vingt un");
+        assert_eq!(fr("12 %"), "douze pour cent");
+    }
+
+    #[test]
+    fn decimal_separator_depends_on_the_language() {
+        assert_eq!(fr("10,5"), "dix virgule cinq");
+        assert_eq!(fr("1.000"), "mille");
+        assert_eq!(fr("1.234.567,5"), fr("1 234 567,5"));
+        assert_eq!(en("10.5"), "ten point five");
+    }
+
+    #[test]
+    fn other_digits_are_left_alone() {
+        assert_eq!(en("１/２/２０２０"), "１ ２ ２０２０");
+        assert_eq!(fr("２０２０-０１-０２ ３e"), "２０２０-０１-０２ ３e");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(en("03/04/2020"), "march fourth twenty twenty");
+        assert_eq!(fr("03/04/2020"), "trois avril deux mille vingt");
+        assert_eq!(
+            fr("01/05/1999"),
+            "premier mai mille neuf cent quatre vingt dix neuf"
+        );
+        assert_eq!(en("2020-02-29"), "february twenty ninth twenty twenty");
+        assert_eq!(en("1999"), "one thousand nine hundred ninety nine");
+    }
+
+    #[test]
+    fn impossible_dates_are_not_read_as_dates() {
+        assert!(!en("2020-02-30").contains("february"));
+        assert!(!en("2021-02-29").contains("february"));
+        assert!(!en("04/31/2020").contains("april"));
+        assert!(!fr("31/04/2020").contains("avril"));
+    }
+
+    #[test]
+    fn currencies() {
+        assert_eq!(
+            en("$5.50 or €1 or £12.01"),
+            "five dollars and fifty cents or one euro or twelve pounds and one penny"
+        );
+        assert_eq!(
+            fr("5,50 € ou 1 €"),
+            "cinq euros et cinquante centimes ou un euro"
+        );
+        assert_eq!(fr("2,01 £"), "deux livres et un centime");
+    }
+
+    #[test]
+    fn ordinals() {
+        assert_eq!(
+            en("the 1st, 2nd, 3rd, 21st and 100th"),
+            "the first, second, third, twenty first and one hundredth"
+        );
+        assert_eq!(
+            fr("la 1re, le 1er, le 2e et le 21e"),
+            "la première, le premier, le deuxième et le vingt et unième"
+        );
+    }
+}
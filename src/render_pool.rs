@@ -0,0 +1,341 @@
+//! Pool of threads running the GES pipelines.
+//!
+//! A pipeline blocks its thread until the rendering is done, so that it must
+//! never run on the actor runtime. Jobs are queued by priority: interactive
+//! previews first, then the transcoding of the source videos, then exports.
+//! Jobs of a same priority run in the order they were queued.
+
+use futures::channel::oneshot;
+use once_cell::sync::Lazy;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Condvar, Mutex};
+
+const DEFAULT_RENDER_WORKERS: usize = 2;
+
+pub static RENDER_POOL: Lazy<RenderPool> = Lazy::new(RenderPool::from_env);
+
+/// Lowest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Export,
+    /// Clips of the search results, which must not delay the previews
+    Search,
+    Source,
+    Interactive,
+}
+
+/// The job was dropped without giving a result, its pipeline panicked
+#[derive(Debug)]
+pub struct JobLost;
+
+pub type JobId = u64;
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Lets a queued or running render job be cancelled. The job itself checks
+/// whether it has been.
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    pub id: JobId,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn new() -> Self {
+        JobHandle {
+            id: NEXT_JOB_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct QueuedJob {
+    priority: Priority,
+    sequence: u64,
+    job: Job,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.sequence == other.sequence
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    /// The greatest job runs first
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<QueuedJob>,
+    next_sequence: u64,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+/// Files being written by a job, with the callers waiting for it to end
+type InFlightPaths = Mutex<HashMap<PathBuf, Vec<oneshot::Sender<()>>>>;
+
+pub struct RenderPool {
+    shared: Arc<Shared>,
+    in_flight: Arc<InFlightPaths>,
+}
+
+/// Ends the exclusive use of a path once the job ends, even if the caller
+/// stopped waiting for it
+struct InFlight {
+    paths: Arc<InFlightPaths>,
+    path: PathBuf,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let waiters = self
+            .paths
+            .lock()
+            .unwrap() // panics if panic already happened
+            .remove(&self.path)
+            .unwrap_or_default();
+        for waiter in waiters {
+            let _ = waiter.send(());
+        }
+    }
+}
+
+impl RenderPool {
+    /// The number of threads is set with `RENDER_WORKERS`
+    pub fn from_env() -> Self {
+        let size = std::env::var("RENDER_WORKERS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RENDER_WORKERS)
+            .max(1);
+        let shared = Arc::new(Shared::default());
+        for i in 0..size {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(format!("render-{}", i))
+                .spawn(move || work(&shared))
+                .expect("Cannot start the render threads");
+        }
+        RenderPool {
+            shared,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queues a job, and waits for its result
+    pub async fn run<T, F>(&self, priority: Priority, job: F) -> Result<T, JobLost>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job = Box::new(move || {
+            // The caller may not wait for the result anymore
+            let _ = sender.send(job());
+        });
+        {
+            let mut queue = self.shared.queue.lock().unwrap(); // panics if panic already happened
+            let sequence = queue.next_sequence;
+            queue.next_sequence += 1;
+            queue.jobs.push(QueuedJob {
+                priority,
+                sequence,
+                job,
+            });
+        }
+        self.shared.available.notify_one();
+        receiver.await.map_err(|_| JobLost)
+    }
+
+    /// Queues a job writing `path` once no other job writes it, and waits
+    /// for its result. A job queued while another writes the same path only
+    /// runs after it ended.
+    pub async fn run_exclusive<T, F>(
+        &self,
+        path: PathBuf,
+        priority: Priority,
+        job: F,
+    ) -> Result<T, JobLost>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        loop {
+            let previous = {
+                let mut in_flight = self.in_flight.lock().unwrap(); // panics if panic already happened
+                match in_flight.get_mut(&path) {
+                    Some(waiters) => {
+                        let (sender, receiver) = oneshot::channel();
+                        waiters.push(sender);
+                        receiver
+                    }
+                    None => {
+                        in_flight.insert(path.clone(), vec![]);
+                        break;
+                    }
+                }
+            };
+            let _ = previous.await;
+        }
+        let in_flight = InFlight {
+            paths: self.in_flight.clone(),
+            path,
+        };
+        self.run(priority, move || {
+            let _in_flight = in_flight;
+            job()
+        })
+        .await
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap(); // panics if panic already happened
+            loop {
+                match queue.jobs.pop() {
+                    Some(queued) => break queued.job,
+                    None => queue = shared.available.wait(queue).unwrap(),
+                }
+            }
+        };
+        // A failing pipeline must not take the thread down with it
+        if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            println!("A render job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_on_the_same_path_do_not_overlap() {
+        let pool = RenderPool {
+            shared: Arc::new(Shared::default()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        };
+        for _ in 0..2 {
+            let shared = pool.shared.clone();
+            std::thread::spawn(move || work(&shared));
+        }
+        let running = Arc::new(AtomicUsize::new(0));
+        let job = |running: Arc<AtomicUsize>| {
+            move || {
+                let before = running.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                running.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                before
+            }
+        };
+        let path = PathBuf::from("same.mp4");
+        let (a, b) = futures::executor::block_on(futures::future::join(
+            pool.run_exclusive(path.clone(), Priority::Interactive, job(running.clone())),
+            pool.run_exclusive(path, Priority::Interactive, job(running.clone())),
+        ));
+        assert_eq!(a.unwrap(), 0);
+        assert_eq!(b.unwrap(), 0);
+    }
+
+    #[test]
+    fn paths_stay_in_use_until_their_job_ends() {
+        let pool = RenderPool {
+            shared: Arc::new(Shared::default()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let path = PathBuf::from("dropped.mp4");
+        futures::executor::block_on(async {
+            let run = pool.run_exclusive(path.clone(), Priority::Interactive, || ());
+            futures::pin_mut!(run);
+            // Queued, then the caller stops waiting
+            assert!(futures::poll!(&mut run).is_pending());
+        });
+        assert!(pool.in_flight.lock().unwrap().contains_key(&path));
+
+        let shared = pool.shared.clone();
+        std::thread::spawn(move || work(&shared));
+        for _ in 0..100 {
+            if !pool.in_flight.lock().unwrap().contains_key(&path) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the path is still in use");
+    }
+
+    #[test]
+    fn higher_priority_runs_first() {
+        let pool = RenderPool {
+            shared: Arc::new(Shared::default()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let order = Arc::new(Mutex::new(vec![]));
+        let job = |order: Arc<Mutex<Vec<Priority>>>, priority| {
+            move || order.lock().unwrap().push(priority)
+        };
+        let export = pool.run(Priority::Export, job(order.clone(), Priority::Export));
+        let preview = pool.run(
+            Priority::Interactive,
+            job(order.clone(), Priority::Interactive),
+        );
+        let source = pool.run(Priority::Source, job(order.clone(), Priority::Source));
+        let search = pool.run(Priority::Search, job(order.clone(), Priority::Search));
+        futures::executor::block_on(async {
+            let all = futures::future::join4(export, preview, source, search);
+            futures::pin_mut!(all);
+            // Queued before any thread takes them
+            assert!(futures::poll!(&mut all).is_pending());
+            let shared = pool.shared.clone();
+            std::thread::spawn(move || work(&shared));
+            let (export, preview, source, search) = all.await;
+            assert!(export.is_ok() && preview.is_ok() && source.is_ok() && search.is_ok());
+        });
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![
+                Priority::Interactive,
+                Priority::Source,
+                Priority::Search,
+                Priority::Export
+            ]
+        );
+    }
+}
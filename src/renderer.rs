@@ -1,112 +1,318 @@
-use crate::data::{Phonem, PreviewId, Video, YoutubeId};
+use crate::data::{Combo, Phonem, PreviewId, Video, YoutubeId};
+use crate::error::RenderError;
+use crate::export::{Caption, ExportOptions};
+use crate::render_pool::{JobHandle, Priority, RENDER_POOL};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
 use ges::prelude::*;
 use gst::ClockTime;
 use gst_pbutils::{
-    EncodingAudioProfileBuilder, EncodingContainerProfileBuilder, EncodingVideoProfileBuilder,
+    EncodingAudioProfileBuilder, EncodingContainerProfileBuilder, EncodingProfile,
+    EncodingVideoProfileBuilder,
 };
 
 type BoxResult = Result<(), Box<dyn std::error::Error>>;
 
-fn render_pipeline(
-    pipeline: &ges::Pipeline,
-    out_uri: &str,
-    out_caps: Option<&gst::Caps>,
-) -> BoxResult {
+/// Interval between two queries of the position of a rendering pipeline
+const PROGRESS_INTERVAL_MS: u64 = 500;
+
+/// Progress of a rendering
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub percent: u8,
+    /// Estimated remaining time, in seconds
+    pub eta: Option<f64>,
+}
+
+/// Encoding of the rendered files. `video_format` replaces the caps of the
+/// video codec of the options.
+fn encoding_profile(
+    options: &ExportOptions,
+    video_format: Option<&gst::Caps>,
+) -> Result<EncodingProfile, Box<dyn std::error::Error>> {
+    if let Some(format) = options.audio_only {
+        let audio_profile = EncodingAudioProfileBuilder::new()
+            .format(&format.caps())
+            .presence(0)
+            .build()?;
+        return Ok(match format.container_caps() {
+            Some(caps) => EncodingContainerProfileBuilder::new()
+                .name("container")
+                .format(&caps)
+                .add_profile(&(audio_profile))
+                .build()?
+                .upcast(),
+            None => audio_profile.upcast(),
+        });
+    }
+
+    // Animated images are a single silent video stream
+    if let Some(animation) = &options.animation {
+        let video_profile = EncodingVideoProfileBuilder::new()
+            .format(&animation.format.caps())
+            .restriction(&animation.restriction())
+            .presence(0)
+            .build()?;
+        return Ok(video_profile.upcast());
+    }
+
     let audio_profile = EncodingAudioProfileBuilder::new()
-        .format(&gst::Caps::new_simple("audio/mpeg", &[]))
+        .format(&options.audio_codec.caps())
         .presence(0)
         .build()?;
 
-    // Every videostream piped into the encodebin should be encoded using vp9.
-    let video_profile = EncodingVideoProfileBuilder::new()
-        .format(out_caps.unwrap_or(&gst::Caps::new_simple("video/x-h264", &[])))
-        .presence(0)
-        .build()?;
+    let codec_caps = options.video_codec.caps();
+    let mut video_profile = EncodingVideoProfileBuilder::new()
+        .format(video_format.unwrap_or(&codec_caps))
+        .presence(0);
+    if let Some(height) = options.resolution.height() {
+        let restriction = gst::Caps::new_simple("video/x-raw", &[("height", &height)]);
+        video_profile = video_profile.restriction(&restriction);
+    }
+    let video_profile = video_profile.build()?;
 
-    // All streams are then finally combined into a mp4 container.
+    // All streams are then finally combined into the container.
     let container_profile = EncodingContainerProfileBuilder::new()
         .name("container")
-        .format(&gst::Caps::new_simple("video/quicktime", &[]))
+        .format(&options.container.caps())
         .add_profile(&(video_profile))
         .add_profile(&(audio_profile))
         .build()?;
+    Ok(container_profile.upcast())
+}
 
-    pipeline.set_render_settings(out_uri, &container_profile)?;
+fn render_pipeline(
+    pipeline: &ges::Pipeline,
+    out_uri: &str,
+    profile: &EncodingProfile,
+    on_progress: &dyn Fn(Progress),
+    job: Option<&JobHandle>,
+) -> BoxResult {
+    pipeline.set_render_settings(out_uri, profile)?;
 
     pipeline.set_mode(ges::PipelineFlags::RENDER)?;
 
     pipeline.set_state(gst::State::Playing)?;
 
     let bus = pipeline.bus().ok_or("No bus")?;
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+    let started = Instant::now();
+    let mut last_query = started;
+    let mut last_percent = None;
+    let mut failure = None;
+    loop {
         use gst::MessageView;
 
-        match msg.view() {
-            MessageView::Eos(..) => break,
-            MessageView::Error(err) => {
+        let msg = bus.timed_pop(Some(ClockTime::from_mseconds(PROGRESS_INTERVAL_MS)));
+        match msg.as_ref().map(|m| m.view()) {
+            Some(MessageView::Eos(..)) => break,
+            Some(MessageView::Error(err)) => {
                 println!(
                     "Error from {:?}: {} ({:?})",
                     err.src().map(|s| s.path_string()),
                     err.error(),
                     err.debug()
                 );
+                failure = Some(err.error().to_string());
                 break;
             }
             _ => (),
         }
+
+        if matches!(job, Some(job) if job.is_cancelled()) {
+            failure = Some("Cancelled".to_owned());
+            break;
+        }
+
+        if last_query.elapsed().as_millis() < PROGRESS_INTERVAL_MS as u128 {
+            continue;
+        }
+        last_query = Instant::now();
+        let position = pipeline.query_position::<ClockTime>();
+        let duration = pipeline.query_duration::<ClockTime>();
+        if let (Some(position), Some(duration)) = (position, duration) {
+            if duration.nseconds() == 0 {
+                continue;
+            }
+            let fraction = (position.nseconds() as f64 / duration.nseconds() as f64).min(1.0);
+            let percent = (fraction * 100.0) as u8;
+            // Only changes are reported
+            if last_percent != Some(percent) {
+                last_percent = Some(percent);
+                let elapsed = started.elapsed().as_secs_f64();
+                let eta = if fraction > 0.0 {
+                    Some(elapsed * (1.0 - fraction) / fraction)
+                } else {
+                    None
+                };
+                on_progress(Progress { percent, eta });
+            }
+        }
     }
 
     pipeline.set_state(gst::State::Null)?;
 
-    Ok(())
+    match failure {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
 }
 
-pub fn preview(
-    videos: &[Arc<Video>],
-    phonems: &[Phonem],
-) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
-    let yt_ids = videos
-        .iter()
-        .map(|v| v.id.clone())
-        .collect::<Vec<YoutubeId>>();
-    let id = PreviewId::from_project_sentence(&yt_ids, phonems);
-    let p = id.path();
-    if !p.exists() {
-        render_phonems(videos, &phonems, &p, true)
-    } else {
-        Ok(())
-    }
-    .map(|()| p)
+/// What a render job produces
+enum Output {
+    Preview,
+    Export(ExportOptions, Vec<Caption>),
 }
 
-pub fn render(
-    videos: &[Arc<Video>],
-    phonems: &[Phonem],
-) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+/// Low resolution preview, rendered before any queued export
+pub async fn preview(
+    videos: Vec<Arc<Video>>,
+    phonems: Combo,
+    handle: JobHandle,
+) -> Result<PathBuf, RenderError> {
+    let output = Output::Preview;
+    render_in_pool(
+        Priority::Interactive,
+        videos,
+        phonems,
+        output,
+        handle,
+        |_| (),
+    )
+    .await
+}
+
+/// Preview of a search result, rendered after the previews of the rows
+pub async fn search_clip(
+    videos: Vec<Arc<Video>>,
+    phonems: Combo,
+    handle: JobHandle,
+) -> Result<PathBuf, RenderError> {
+    render_in_pool(
+        Priority::Search,
+        videos,
+        phonems,
+        Output::Preview,
+        handle,
+        |_| (),
+    )
+    .await
+}
+
+/// Full resolution rendering, reporting its progress as it goes. Captions are
+/// only drawn on animations.
+pub async fn render<F>(
+    videos: Vec<Arc<Video>>,
+    phonems: Combo,
+    options: ExportOptions,
+    captions: Vec<Caption>,
+    handle: JobHandle,
+    on_progress: F,
+) -> Result<PathBuf, RenderError>
+where
+    F: Fn(Progress) + Send + 'static,
+{
+    render_in_pool(
+        Priority::Export,
+        videos,
+        phonems,
+        Output::Export(options, captions),
+        handle,
+        on_progress,
+    )
+    .await
+}
+
+/// Renders the phonems on the render pool, unless they already have been
+async fn render_in_pool<F>(
+    priority: Priority,
+    videos: Vec<Arc<Video>>,
+    phonems: Combo,
+    output: Output,
+    handle: JobHandle,
+    on_progress: F,
+) -> Result<PathBuf, RenderError>
+where
+    F: Fn(Progress) + Send + 'static,
+{
     let yt_ids = videos
         .iter()
         .map(|v| v.id.clone())
         .collect::<Vec<YoutubeId>>();
-    let id = PreviewId::from_project_sentence(&yt_ids, phonems);
-    let p = id.render_path();
-    if !p.exists() {
-        render_phonems(videos, &phonems, &p, false)
-    } else {
-        Ok(())
+    let id = PreviewId::from_project_sentence(&yt_ids, &phonems);
+    let p = match &output {
+        Output::Preview => id.path(),
+        Output::Export(options, captions) => id.render_path(options, captions),
+    };
+    let job_path = p.clone();
+    let job = move || {
+        let p = job_path;
+        // Cancelled while queued
+        if handle.is_cancelled() {
+            return Err(RenderError::Cancelled);
+        }
+        if !p.exists() {
+            if let Some(folder) = p.parent() {
+                // The audio folder is only needed by the first audio export
+                let _ = std::fs::create_dir_all(folder);
+            }
+            // Written under a name of its own, so that the file is never
+            // found half written
+            let part = p.with_extension(format!("{}.part", handle.id));
+            let res = render_phonems(&videos, &phonems, &part, &output, &on_progress, &handle)
+                .and_then(|()| Ok(std::fs::rename(&part, &p)?));
+            if let Err(e) = res {
+                let _ = std::fs::remove_file(&part);
+                if handle.is_cancelled() {
+                    return Err(RenderError::Cancelled);
+                }
+                return Err(RenderError::Pipeline(e.to_string()));
+            }
+        }
+        // Checked on cached files too, the limit may have been lowered since
+        check_size(&p, &output, crate::export::max_animation_size())?;
+        Ok(p)
+    };
+    // Jobs rendering the same file run one after the other, the later ones
+    // finding the file of the first
+    RENDER_POOL
+        .run_exclusive(p, priority, job)
+        .await
+        .map_err(|_| RenderError::JobLost)?
+}
+
+/// Removes the animations heavier than `max` bytes, the other outputs having
+/// no limit
+fn check_size(path: &std::path::Path, output: &Output, max: u64) -> Result<(), RenderError> {
+    let animated = matches!(output, Output::Export(o, _) if o.animation.is_some());
+    if !animated {
+        return Ok(());
     }
-    .map(|()| p)
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size > max {
+        let _ = std::fs::remove_file(path);
+        return Err(RenderError::TooLarge { size, max });
+    }
+    Ok(())
 }
 
 fn render_phonems(
     videos: &[Arc<Video>],
     phonems: &[Phonem],
     out_path: &std::path::Path,
-    small: bool,
+    output: &Output,
+    on_progress: &dyn Fn(Progress),
+    handle: &JobHandle,
 ) -> BoxResult {
     ges::init()?; // TODO: peut etre l'enlever ?
 
+    let default_options = ExportOptions::default();
+    let (small, options, captions) = match output {
+        Output::Preview => (true, &default_options, &[][..]),
+        Output::Export(options, captions) => (false, options, &captions[..]),
+    };
+
     let uri = format!(
         "file://{}",
         out_path
@@ -114,20 +320,38 @@ fn render_phonems(
             .ok_or("Output path is not valid unicode")?
     );
 
-    // Begin by creating a timeline with audio and video tracks
+    // Begin by creating a timeline with audio and video tracks. Audio-only
+    // exports have no video track, so that no frame is ever decoded, and
+    // animations have no audio track.
     let timeline = ges::Timeline::new();
 
-    let video_caps = gst::Caps::new_simple("video/x-raw", &[]);
-    let audio_track = ges::Track::new(
-        ges::TrackType::AUDIO,
-        &gst::Caps::new_simple("audio/x-raw", &[]),
-    );
-    let video_track = ges::Track::new(ges::TrackType::VIDEO, &video_caps);
-    video_track.set_restriction_caps(&video_caps);
-
-    timeline.add_track(&video_track)?;
-    timeline.add_track(&audio_track)?;
-
+    let with_video = options.audio_only.is_none();
+    let with_audio = options.animation.is_none();
+    if with_video {
+        let video_caps = gst::Caps::new_simple("video/x-raw", &[]);
+        let video_track = ges::Track::new(ges::TrackType::VIDEO, &video_caps);
+        video_track.set_restriction_caps(&video_caps);
+        timeline.add_track(&video_track)?;
+    }
+    if with_audio {
+        let audio_track = ges::Track::new(
+            ges::TrackType::AUDIO,
+            &gst::Caps::new_simple("audio/x-raw", &[]),
+        );
+        timeline.add_track(&audio_track)?;
+    }
+    let track_types = match (with_video, with_audio) {
+        (true, false) => ges::TrackType::VIDEO,
+        (false, true) => ges::TrackType::AUDIO,
+        _ => ges::TrackType::VIDEO | ges::TrackType::AUDIO,
+    };
+
+    // Captions are on the first layer, so that they are drawn over the clips
+    let caption_layer = if captions.is_empty() {
+        None
+    } else {
+        Some(timeline.append_layer())
+    };
     // Create a new layer that will contain our timed clips.
     let layer = timeline.append_layer();
     let pipeline = ges::Pipeline::new();
@@ -138,27 +362,78 @@ fn render_phonems(
         .map(|v| if small { &v.lite_asset } else { &v.asset })
         .collect(); // TODO once per project
 
-    phonems.iter().try_fold(
+    // Timeline start of every phonem
+    let mut starts_ms = Vec::with_capacity(phonems.len() + 1);
+    let end_ms = phonems.iter().try_fold(
         0,
         |timeline_start_ms: u64, e: &Phonem| -> Result<u64, Box<dyn std::error::Error>> {
+            starts_ms.push(timeline_start_ms);
             let start_ms = (e.start * 1000.0).round() as u64;
             let duration_ms = ((e.end - e.start) * 1000.0).round() as u64;
-            let asset = assets[e.video_index as usize];
+            if e.gap {
+                // Black and silent clip, so that pauses at the end are kept too
+                let silence = ges::TestClip::new().ok_or("Failed to create a pause")?;
+                silence.set_vpattern(ges::VideoTestPattern::Black);
+                silence.set_mute(true);
+                silence.set_start(ClockTime::from_mseconds(timeline_start_ms));
+                silence.set_duration(ClockTime::from_mseconds(duration_ms));
+                layer.add_clip(&silence)?;
+                return Ok(timeline_start_ms + duration_ms);
+            }
+            // The phonems come from the analyzer and from stored projects
+            let asset = *assets
+                .get(e.video_index as usize)
+                .ok_or_else(|| format!("The phonem refers to a missing video {}", e.video_index))?;
             // let clip = layer.add_asset(
             layer.add_asset(
                 asset,
                 ClockTime::from_mseconds(timeline_start_ms),
                 ClockTime::from_mseconds(start_ms),
                 ClockTime::from_mseconds(duration_ms),
-                ges::TrackType::VIDEO | ges::TrackType::AUDIO,
+                track_types,
             )?;
             // let effect = ges::Effect::new("head_tracking").expect("Failed to create effect");
             // clip.add(&effect)?;
             Ok(timeline_start_ms + duration_ms)
         },
     )?;
+    starts_ms.push(end_ms);
+
+    if let Some(caption_layer) = caption_layer {
+        let mut first = 0;
+        for caption in captions {
+            let last = (first + caption.phonems).min(phonems.len());
+            let (start_ms, caption_end_ms) = (starts_ms[first], starts_ms[last]);
+            first = last;
+            if caption.text.is_empty() || caption_end_ms <= start_ms {
+                continue;
+            }
+            let title = ges::TitleClip::new().ok_or("Failed to create a caption")?;
+            title.set_text(Some(&caption.text));
+            title.set_valignment(ges::TextVAlign::Bottom);
+            title.set_start(ClockTime::from_mseconds(start_ms));
+            title.set_duration(ClockTime::from_mseconds(caption_end_ms - start_ms));
+            caption_layer.add_clip(&title)?;
+        }
+    }
 
-    render_pipeline(&pipeline, &uri, None)
+    // Bitrate presets and animation settings are set on the encoders as
+    // encodebin creates them
+    let settings = options.clone();
+    pipeline.connect_deep_element_added(move |_, _, element| {
+        let encoder = match element.factory() {
+            Some(factory) => factory.name(),
+            None => return,
+        };
+        for (property, value) in crate::export::encoder_settings(&encoder, &settings) {
+            if element.set_property_from_str(property, &value).is_err() {
+                println!("Cannot set {} of {}", property, encoder);
+            }
+        }
+    });
+
+    let profile = encoding_profile(options, None)?;
+    render_pipeline(&pipeline, &uri, &profile, on_progress, Some(handle))
 }
 
 pub fn render_main_video(
@@ -227,5 +502,48 @@ pub fn render_main_video(
         },
     );
 
-    render_pipeline(&pipeline, &out_uri, Some(caps))
+    let profile = encoding_profile(&ExportOptions::default(), Some(caps))?;
+    let res = render_pipeline(&pipeline, &out_uri, &profile, &|_| (), None);
+    if res.is_err() {
+        // A partial file would be taken for a finished one
+        let _ = std::fs::remove_file(out_path);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{AnimationOptions, AudioFormat};
+
+    fn export(options: ExportOptions) -> Output {
+        Output::Export(options, vec![])
+    }
+
+    #[test]
+    fn only_animations_are_size_capped() {
+        let path = std::env::temp_dir().join(format!("sm-size-{}.gif", std::process::id()));
+        let animation = export(ExportOptions {
+            animation: Some(AnimationOptions::default()),
+            ..ExportOptions::default()
+        });
+        let audio = export(ExportOptions {
+            audio_only: Some(AudioFormat::Wav),
+            ..ExportOptions::default()
+        });
+
+        std::fs::write(&path, [0; 10]).unwrap();
+        assert!(check_size(&path, &audio, 5).is_ok());
+        assert!(check_size(&path, &Output::Preview, 5).is_ok());
+        assert!(check_size(&path, &animation, 10).is_ok());
+        assert!(path.exists());
+
+        let res = check_size(&path, &animation, 5);
+        assert!(matches!(
+            res,
+            Err(RenderError::TooLarge { size: 10, max: 5 })
+        ));
+        // Never sent, nor found again in cache
+        assert!(!path.exists());
+    }
 }
@@ -1,8 +1,20 @@
-use crate::data::{AmbiguityError, AnalysisId, AnalysisResult, Project, Video};
-use chashmap::CHashMap;
+use crate::analyzer::{
+    AnalysisFuture, Analyzer, BatchFuture, DiagnosisFuture, LanguagesFuture, OccurrencesFuture,
+    SearchFuture, VocabularyFuture, WordsFuture,
+};
+use crate::cache::{AnalysisCache, CacheStats};
+use crate::data::{
+    get_vocabulary_path, AmbiguityError, AnalysisError, AnalysisId, AnalysisRequest,
+    AnalysisResult, Combo, Phonem, Project, SearchQuery, Segment, VideoVocabulary, VocabularyEntry,
+    WordCoverage, WordDiagnostic, WordStatus,
+};
+use crate::sm_pool::{WorkerError, WorkerPool};
 use once_cell::sync::Lazy;
-use std::sync::{Arc, RwLock};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use tokio::process::Command;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 fn get_command() -> Command {
     cfg_if::cfg_if! {
@@ -14,63 +26,724 @@ fn get_command() -> Command {
     }
 }
 
-type AnalysisCache = CHashMap<AnalysisId, Arc<AnalysisResult>>;
-static ANALYSIS_CACHE: Lazy<RwLock<AnalysisCache>> =
-    Lazy::new(|| RwLock::new(AnalysisCache::new()));
+static WORKERS: Lazy<WorkerPool> = Lazy::new(|| WorkerPool::from_env(get_command));
 
-fn add_in_cache(key: AnalysisId, val: Arc<AnalysisResult>) {
-    ANALYSIS_CACHE.read().unwrap().insert(key, val); // panics if panic already happened
+static ANALYSIS_CACHE: Lazy<Mutex<AnalysisCache>> = Lazy::new(|| Mutex::new(AnalysisCache::open()));
+
+const DEFAULT_MAX_ANALYSES: usize = 4;
+/// Analyses sent to the analyzer in a single call
+const BATCH_SIZE: usize = 32;
+
+/// Analyzer calls running at the same time, set with `SM_MAX_ANALYSES`
+static ANALYZER_PERMITS: Lazy<Semaphore> = Lazy::new(|| {
+    let max = std::env::var("SM_MAX_ANALYSES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ANALYSES);
+    Semaphore::new(max.max(1))
+});
+
+/// Waits until the analyzer can be called without exceeding the limit
+async fn analyzer_permit() -> SemaphorePermit<'static> {
+    ANALYZER_PERMITS.acquire().await
+}
+
+async fn add_in_cache(analyzer: &dyn Analyzer, key: &AnalysisId, val: Arc<AnalysisResult>) {
+    crate::cache::insert(analyzer.cache(), key, val).await
+}
+
+async fn get_from_cache(analyzer: &dyn Analyzer, key: &AnalysisId) -> Option<Arc<AnalysisResult>> {
+    crate::cache::get(analyzer.cache(), key).await
+}
+
+pub fn cache_stats(analyzer: &dyn Analyzer) -> CacheStats {
+    analyzer.cache().lock().unwrap().stats() // panics if panic already happened
+}
+
+/// Saves the order of use of the cached analyses, for the next start
+pub fn save_cache(analyzer: &dyn Analyzer) {
+    analyzer.cache().lock().unwrap().save() // panics if panic already happened
+}
+
+/// Analysis through a persistent worker
+async fn analyze_with_worker(
+    request: &AnalysisRequest,
+) -> Result<Result<AnalysisResult, AmbiguityError>, WorkerError> {
+    let params = serde_json::to_value(request).map_err(|_| WorkerError::Protocol)?;
+    match WORKERS.call("analyze", params).await? {
+        Ok(result) => serde_json::from_value(result).map(Ok),
+        Err(error) => serde_json::from_value(error).map(Err),
+    }
+    .map_err(|_| WorkerError::Protocol)
+}
+
+/// Analysis in a dedicated sm-interface process
+async fn analyze_with_command(request: &AnalysisRequest) -> Result<AnalysisResult, AmbiguityError> {
+    let mut command = get_command();
+    // Options which do not fit in the arguments are given as json in `SM_REQUEST`
+    let json_request = serde_json::to_string(request).unwrap(); // Always serializable
+                                                                // The process is killed if the analysis is superseded
+    let output = command
+        .args(&[&request.sentence, &request.seed])
+        .args(&request.urls)
+        .env("SM_REQUEST", json_request)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .expect("Couldn't launch sm-interface.");
+
+    let err_data = output.stderr;
+    let out_data = output.stdout;
+    let end = if out_data.len() > 0 {
+        out_data.len() - 1
+    } else {
+        0
+    };
+    let start = out_data[..end].iter().rposition(|x| *x == 0xa).unwrap_or(0);
+    let res: serde_json::Result<AnalysisResult> = serde_json::from_slice(&out_data[start..]);
+    res.map_err(|_| -> AmbiguityError {
+        match serde_json::from_slice(&out_data) {
+            Ok(res) => res,
+            Err(_) => panic!(
+                "STDOUT\n{}\n\nSTDERR\n{}",
+                String::from_utf8(out_data).unwrap(), // panics in panic
+                String::from_utf8(err_data).unwrap(), // panics in panic
+            ),
+        }
+    })
+}
+
+/// Answer of a worker for one request of a batch:
+/// `{"result": [...]}` or `{"error": {"word": "..."}}`
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BatchOutcome {
+    Result(AnalysisResult),
+    Error(AmbiguityError),
+}
+
+/// Analyzer backed by the sm-interface submodule
+pub struct SmInterface;
+
+impl Analyzer for SmInterface {
+    fn analyze(&self, request: AnalysisRequest) -> AnalysisFuture {
+        Box::pin(async move {
+            match analyze_with_worker(&request).await {
+                Ok(res) => res.map_err(AnalysisError::from),
+                // A dedicated process would take as long
+                Err(WorkerError::Timeout) => {
+                    println!("The analysis of \"{}\" timed out", request.sentence);
+                    Err(AnalysisError::Timeout)
+                }
+                // Older sm-interface builds, or no worker available
+                Err(_) => analyze_with_command(&request)
+                    .await
+                    .map_err(AnalysisError::from),
+            }
+        })
+    }
+
+    fn cache(&self) -> &Mutex<AnalysisCache> {
+        &ANALYSIS_CACHE
+    }
+
+    fn analyze_batch(&self, requests: Vec<AnalysisRequest>) -> BatchFuture {
+        Box::pin(async move {
+            let params = serde_json::json!({ "requests": requests });
+            match WORKERS.call("analyze_batch", params).await {
+                Ok(Ok(result)) => match serde_json::from_value::<Vec<BatchOutcome>>(result) {
+                    Ok(outcomes) if outcomes.len() == requests.len() => {
+                        return outcomes
+                            .into_iter()
+                            .map(|outcome| match outcome {
+                                BatchOutcome::Result(result) => Ok(result),
+                                BatchOutcome::Error(error) => Err(error.into()),
+                            })
+                            .collect();
+                    }
+                    _ => println!("Invalid answer to a batch of analyses"),
+                },
+                // Analysing the sentences one by one would time out the same way
+                Err(WorkerError::Timeout) => {
+                    println!("The analysis of {} sentences timed out", requests.len());
+                    return requests
+                        .iter()
+                        .map(|_| Err(AnalysisError::Timeout))
+                        .collect();
+                }
+                // Older sm-interface builds analyze one sentence per call
+                Ok(Err(_)) | Err(WorkerError::Unavailable) => (),
+                Err(e) => println!("The analysis of a batch failed: {}", e),
+            }
+            let mut results = Vec::with_capacity(requests.len());
+            for request in requests {
+                results.push(SmInterface.analyze(request).await);
+            }
+            results
+        })
+    }
+
+    fn diagnose(&self, request: AnalysisRequest) -> DiagnosisFuture {
+        Box::pin(async move {
+            let params = serde_json::to_value(&request).unwrap(); // Always serializable
+            match WORKERS.call("diagnose", params).await {
+                Ok(Ok(result)) => serde_json::from_value(result).ok(),
+                // Older sm-interface builds only report the first problem
+                _ => None,
+            }
+        })
+    }
+
+    fn occurrences(&self, urls: Vec<String>, phonem: String) -> OccurrencesFuture {
+        Box::pin(async move {
+            let params = serde_json::json!({ "urls": urls, "phonem": phonem });
+            match WORKERS.call("occurrences", params).await {
+                Ok(Ok(result)) => serde_json::from_value(result).unwrap_or_default(),
+                // Older sm-interface builds cannot locate phonems
+                _ => vec![],
+            }
+        })
+    }
+
+    fn search(&self, urls: Vec<String>, query: SearchQuery) -> SearchFuture {
+        Box::pin(async move {
+            let params = serde_json::json!({ "urls": urls, "query": query });
+            match WORKERS.call("search", params).await {
+                Ok(Ok(result)) => serde_json::from_value(result).unwrap_or_default(),
+                // Older sm-interface builds cannot search
+                _ => vec![],
+            }
+        })
+    }
+
+    fn languages(&self) -> LanguagesFuture {
+        Box::pin(async move {
+            match WORKERS.call("languages", serde_json::Value::Null).await {
+                Ok(Ok(result)) => serde_json::from_value(result).unwrap_or_default(),
+                // Older sm-interface builds do not report their languages
+                _ => vec![],
+            }
+        })
+    }
+
+    fn similar_words(
+        &self,
+        urls: Vec<String>,
+        language: Option<String>,
+        word: String,
+    ) -> WordsFuture {
+        Box::pin(async move {
+            let params = serde_json::json!({ "urls": urls, "language": language, "word": word });
+            match WORKERS.call("similar", params).await {
+                Ok(Ok(result)) => serde_json::from_value(result).unwrap_or_default(),
+                // Older sm-interface builds cannot compare words
+                _ => vec![],
+            }
+        })
+    }
+
+    fn vocabulary(&self, urls: Vec<String>, video: u8) -> VocabularyFuture {
+        Box::pin(async move {
+            let url = urls.get(video as usize)?;
+            let params = serde_json::json!({ "url": url });
+            match WORKERS.call("vocabulary", params).await {
+                Ok(Ok(result)) => serde_json::from_value(result).ok(),
+                // Older sm-interface builds cannot list the vocabulary
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Analysis of the segment if it has been done recently enough to still be
+/// in memory. The disk is not read, so that actor handlers never wait on it.
+pub fn cached_analysis(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    segment: &Segment,
+) -> Option<Arc<AnalysisResult>> {
+    let key = AnalysisId::from_request(&AnalysisRequest::new(project, segment));
+    analyzer.cache().lock().unwrap().get_loaded(&key) // panics if panic already happened
+}
+
+/// Analysis of the segment if it has been done before, from memory or from
+/// the disk. The analyzer is never called.
+pub async fn stored_analysis(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    segment: &Segment,
+) -> Option<Arc<AnalysisResult>> {
+    let key = AnalysisId::from_request(&AnalysisRequest::new(project, segment));
+    get_from_cache(analyzer, &key).await
 }
 
 pub async fn analyze(
+    analyzer: &dyn Analyzer,
     project: &Project,
-    sentence: &str,
-) -> Result<Arc<AnalysisResult>, AmbiguityError> {
-    let hash_key = AnalysisId::from_project_sentence(project, sentence);
-    match ANALYSIS_CACHE.read().unwrap().get(&hash_key) {
-        // panics if panic already happened
-        Some(result) => Ok((*result).clone()),
-        None => {
-            let urls = project
-                .video_ids
-                .iter()
-                .map(|yt_id| yt_id.id.clone())
-                .collect::<Vec<_>>();
-            let mut command = get_command();
-            let output = command
-                .args(&[sentence, &project.seed])
-                .args(&urls)
-                .output()
-                .await
-                .expect("Couldn't launch sm-interface.");
-
-            let err_data = output.stderr;
-            let out_data = output.stdout;
-            let end = if out_data.len() > 0 {
-                out_data.len() - 1
-            } else {
-                0
+    segment: &Segment,
+) -> Result<Arc<AnalysisResult>, AnalysisError> {
+    let request = AnalysisRequest::new(project, segment);
+    let hash_key = AnalysisId::from_request(&request);
+    if let Some(result) = get_from_cache(analyzer, &hash_key).await {
+        return Ok(result);
+    }
+    let analysis = {
+        let _permit = analyzer_permit().await;
+        analyzer.analyze(request).await
+    };
+    let boxed = Arc::new(analysis?);
+    add_in_cache(analyzer, &hash_key, boxed.clone()).await;
+    Ok(boxed)
+}
+
+/// Analyses of every segment, in order. The sentences which are not cached
+/// are sent to the analyzer in batches, and their results cached.
+pub async fn analyze_all(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    segments: &[Segment],
+) -> Vec<Result<Arc<AnalysisResult>, AnalysisError>> {
+    let requests: Vec<_> = segments
+        .iter()
+        .map(|segment| AnalysisRequest::new(project, segment))
+        .collect();
+    let keys: Vec<_> = requests.iter().map(AnalysisId::from_request).collect();
+
+    let mut results: HashMap<&AnalysisId, Result<Arc<AnalysisResult>, AnalysisError>> =
+        HashMap::new();
+    let mut uncached = vec![];
+    let mut pending = HashSet::new();
+    for (key, request) in keys.iter().zip(requests) {
+        if results.contains_key(key) || !pending.insert(key) {
+            continue;
+        }
+        match get_from_cache(analyzer, key).await {
+            Some(result) => {
+                results.insert(key, Ok(result));
+            }
+            None => uncached.push((key, request)),
+        }
+    }
+
+    for batch in uncached.chunks(BATCH_SIZE) {
+        let requests = batch.iter().map(|(_, request)| request.clone()).collect();
+        let analyses = {
+            let _permit = analyzer_permit().await;
+            analyzer.analyze_batch(requests).await
+        };
+        for ((key, _), analysis) in batch.iter().zip(analyses) {
+            let analysis = match analysis {
+                Ok(res) => {
+                    let boxed = Arc::new(res);
+                    add_in_cache(analyzer, key, boxed.clone()).await;
+                    Ok(boxed)
+                }
+                Err(e) => Err(e),
             };
-            let start = out_data[..end].iter().rposition(|x| *x == 0xa).unwrap_or(0);
-            let res: serde_json::Result<AnalysisResult> =
-                serde_json::from_slice(&out_data[start..]);
-            let res = res.map(|res| {
-                let boxed = Arc::new(res);
-                add_in_cache(hash_key, boxed.clone());
-                boxed
+            results.insert(key, analysis);
+        }
+    }
+
+    let mut analyses = Vec::with_capacity(segments.len());
+    for (key, segment) in keys.iter().zip(segments) {
+        analyses.push(match results.get(key) {
+            Some(result) => result.clone(),
+            // The analyzer did not answer for every request of the batch
+            None => analyze(analyzer, project, segment).await,
+        });
+    }
+    analyses
+}
+
+fn word_status(analysis: Result<Arc<AnalysisResult>, AnalysisError>) -> WordStatus {
+    match analysis {
+        Ok(_) => WordStatus::Ok,
+        Err(AnalysisError::Timeout) => WordStatus::TimedOut,
+        Err(AnalysisError::Ambiguity(e)) if e.candidates.is_empty() => WordStatus::Unavailable,
+        Err(AnalysisError::Ambiguity(e)) => WordStatus::Ambiguous {
+            candidates: e.candidates,
+        },
+    }
+}
+
+/// Statuses of the words of the segment, each one analyzed on its own, for
+/// the analyzers which only report the first problem of a sentence
+async fn diagnose_word_by_word(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    segment: &Segment,
+    words: &[String],
+    failure: Option<&AmbiguityError>,
+) -> Vec<WordStatus> {
+    let failed = |word: &str| matches!(failure, Some(e) if e.word.to_lowercase() == word);
+    let checks: Vec<Segment> = words
+        .iter()
+        .filter(|word| !failed(word))
+        .map(|word| Segment {
+            sentence: word.clone(),
+            raw: true,
+            ..segment.clone()
+        })
+        .collect();
+    let mut analyses = analyze_all(analyzer, project, &checks).await.into_iter();
+    words
+        .iter()
+        .map(|word| match failure {
+            Some(e) if failed(word) => word_status(Err(e.clone().into())),
+            // One analysis per word which did not fail
+            _ => word_status(analyses.next().unwrap()),
+        })
+        .collect()
+}
+
+/// Status of every word of the segment, with its position in the analyzed
+/// sentence. `failure` is the error of the analysis of the segment, if the
+/// caller has it already.
+pub async fn diagnose(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    segment: &Segment,
+    failure: Option<&AmbiguityError>,
+) -> Vec<WordDiagnostic> {
+    let request = AnalysisRequest::new(project, segment);
+    let words = crate::analyzer::tokenize(&request.sentence);
+    let statuses = {
+        let _permit = analyzer_permit().await;
+        analyzer.diagnose(request).await
+    };
+    let statuses = match statuses {
+        Some(statuses) if statuses.len() == words.len() => statuses,
+        _ => {
+            let texts: Vec<_> = words.iter().map(|w| w.text.clone()).collect();
+            diagnose_word_by_word(analyzer, project, segment, &texts, failure).await
+        }
+    };
+    words
+        .into_iter()
+        .zip(statuses)
+        .map(|(word, status)| WordDiagnostic {
+            word: word.text,
+            start: word.start,
+            end: word.end,
+            status,
+        })
+        .collect()
+}
+
+/// Every place of the source videos where the phonem is said
+pub async fn occurrences(analyzer: &dyn Analyzer, project: &Project, phonem: &str) -> Vec<Phonem> {
+    let urls = project.video_ids.iter().map(|id| id.id.clone()).collect();
+    let _permit = analyzer_permit().await;
+    analyzer.occurrences(urls, phonem.to_owned()).await
+}
+
+/// Clips of the source videos matching the query
+pub async fn search(analyzer: &dyn Analyzer, project: &Project, query: &SearchQuery) -> Vec<Combo> {
+    let urls = project.video_ids.iter().map(|id| id.id.clone()).collect();
+    let _permit = analyzer_permit().await;
+    analyzer.search(urls, query.clone()).await
+}
+
+/// Languages the analyzer can read
+pub async fn languages(analyzer: &dyn Analyzer) -> Vec<String> {
+    let _permit = analyzer_permit().await;
+    analyzer.languages().await
+}
+
+/// Whether the analyzer can read the language. Analyzers which do not list
+/// their languages are trusted with any of them.
+pub async fn reads_language(analyzer: &dyn Analyzer, language: &str) -> bool {
+    let languages = languages(analyzer).await;
+    languages.is_empty() || languages.iter().any(|l| l == language)
+}
+
+/// Suggestions given at most for each word the sources cannot say
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Status of every word of a text, with the words the sources can say
+/// instead of the missing ones. Returns the analyzed text, which the word
+/// offsets refer to.
+pub async fn coverage(
+    analyzer: &dyn Analyzer,
+    project: &Project,
+    text: &str,
+) -> (String, Vec<WordCoverage>) {
+    let segment = Segment::new(text);
+    let sentence = AnalysisRequest::new(project, &segment).sentence;
+    let words = diagnose(analyzer, project, &segment, None).await;
+
+    // Synonyms and similar words of every missing word
+    let urls: Vec<String> = project.video_ids.iter().map(|id| id.id.clone()).collect();
+    let mut candidates: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for word in words.iter() {
+        if !matches!(word.status, WordStatus::Unavailable) || candidates.contains_key(&word.word) {
+            continue;
+        }
+        let mut c: Vec<String> = crate::synonyms::synonyms(&word.word, project.language.as_deref())
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let similar = {
+            let _permit = analyzer_permit().await;
+            analyzer
+                .similar_words(urls.clone(), project.language.clone(), word.word.clone())
+                .await
+        };
+        c.extend(similar);
+        candidates.insert(word.word.clone(), c);
+    }
+
+    // Every candidate is analyzed on its own: a sentence made of all of them
+    // would only tell about its first problem with some analyzers
+    let mut seen = HashSet::new();
+    let checks: Vec<Segment> = candidates
+        .values()
+        .flatten()
+        .filter(|c| seen.insert(c.as_str()))
+        .map(|c| Segment {
+            raw: true,
+            ..Segment::new(c)
+        })
+        .collect();
+    let analyses = analyze_all(analyzer, project, &checks).await;
+    let available: HashSet<String> = checks
+        .into_iter()
+        .zip(analyses)
+        .filter(|(_, analysis)| match analysis {
+            Ok(analysis) => !analysis.combos.is_empty(),
+            // Ambiguous words can still be said
+            Err(AnalysisError::Ambiguity(e)) => !e.candidates.is_empty(),
+            Err(AnalysisError::Timeout) => false,
+        })
+        .map(|(check, _)| check.sentence)
+        .collect();
+
+    let words = words
+        .into_iter()
+        .map(|diagnostic| {
+            let suggestions = candidates
+                .get(&diagnostic.word)
+                .map(|c| {
+                    c.iter()
+                        .filter(|w| available.contains(*w))
+                        .take(MAX_SUGGESTIONS)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            WordCoverage {
+                diagnostic,
+                suggestions,
+            }
+        })
+        .collect();
+    (sentence, words)
+}
+
+/// Builds the vocabulary of the source videos which do not have one yet,
+/// and stores it in `.videos/`. The videos must be downloaded.
+pub async fn store_vocabularies(analyzer: &dyn Analyzer, urls: &[String]) {
+    for (video, url) in urls.iter().enumerate() {
+        let path = get_vocabulary_path(url);
+        if async_fs::metadata(&path).await.is_ok() {
+            continue;
+        }
+        let vocabulary = {
+            let _permit = analyzer_permit().await;
+            analyzer.vocabulary(urls.to_vec(), video as u8).await
+        };
+        let vocabulary = match vocabulary {
+            Some(v) => v,
+            None => {
+                println!("The analyzer cannot list the vocabulary of {}", url);
+                continue;
+            }
+        };
+        let json = serde_json::to_vec(&vocabulary).unwrap(); // Always serializable
+        if let Err(e) = async_fs::write(&path, json).await {
+            println!("Cannot store the vocabulary of {}: {}", url, e);
+        }
+    }
+}
+
+/// Vocabulary of a source video, as stored by `store_vocabularies`
+async fn video_vocabulary(url: &str) -> Option<VideoVocabulary> {
+    let bytes = async_fs::read(get_vocabulary_path(url)).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Every word and phonem the source videos of the project can say, the most
+/// frequent first. `None` if the vocabulary of a video is not available.
+pub async fn vocabulary(project: &Project) -> Option<(Vec<VocabularyEntry>, Vec<VocabularyEntry>)> {
+    let urls: Vec<String> = project.video_ids.iter().map(|id| id.id.clone()).collect();
+    let mut words: BTreeMap<String, VocabularyEntry> = BTreeMap::new();
+    let mut phonems: BTreeMap<String, VocabularyEntry> = BTreeMap::new();
+
+    let add = |entries: &mut BTreeMap<String, VocabularyEntry>, text: &str, count, video| {
+        let entry = entries
+            .entry(text.to_owned())
+            .or_insert_with(|| VocabularyEntry {
+                text: text.to_owned(),
+                count: 0,
+                videos: vec![],
             });
-            let res: Result<Arc<AnalysisResult>, AmbiguityError> =
-                res.map_err(|_| -> AmbiguityError {
-                    match serde_json::from_slice(&out_data) {
-                        Ok(res) => res,
-                        Err(_) => panic!(
-                            "STDOUT\n{}\n\nSTDERR\n{}",
-                            String::from_utf8(out_data).unwrap(), // panics in panic
-                            String::from_utf8(err_data).unwrap(), // panics in panic
-                        ),
-                    }
-                });
-            res
+        entry.count += count;
+        entry.videos.push(video);
+    };
+    for (video, url) in urls.iter().enumerate() {
+        let video = video as u8;
+        let vocabulary = video_vocabulary(url).await?;
+        for (word, count) in vocabulary.words.iter() {
+            add(&mut words, word, *count, video);
+        }
+        for (phonem, count) in vocabulary.phonems.iter() {
+            add(&mut phonems, phonem, *count, video);
+        }
+    }
+
+    let sorted = |entries: BTreeMap<String, VocabularyEntry>| {
+        let mut entries: Vec<_> = entries.into_values().collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.text.cmp(&b.text)));
+        entries
+    };
+    Some((sorted(words), sorted(phonems)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::tests::fixture_analyzer;
+    use crate::analyzer::MockAnalyzer;
+    use futures::executor::block_on;
+
+    /// Analyzer which only reports the first problem of a sentence, like
+    /// older sm-interface builds, and suggests words the sources cannot say
+    struct FirstProblemOnly(MockAnalyzer);
+
+    impl Analyzer for FirstProblemOnly {
+        fn analyze(&self, request: AnalysisRequest) -> AnalysisFuture {
+            self.0.analyze(request)
+        }
+
+        fn cache(&self) -> &Mutex<AnalysisCache> {
+            self.0.cache()
         }
+
+        fn similar_words(
+            &self,
+            urls: Vec<String>,
+            language: Option<String>,
+            word: String,
+        ) -> WordsFuture {
+            let similar = self.0.similar_words(urls, language, word);
+            Box::pin(async move {
+                let mut words = vec!["unsaid".to_owned(), "unheard".to_owned()];
+                words.extend(similar.await);
+                words
+            })
+        }
+    }
+
+    fn project() -> Project {
+        Project::new("test", "seed", &["a".to_owned(), "b".to_owned()])
+    }
+
+    #[test]
+    fn analyses_are_cached_by_the_analyzer() {
+        let analyzer = fixture_analyzer();
+        let project = project();
+        let segment = Segment::new("Hello world");
+        let first = block_on(analyze(&analyzer, &project, &segment)).unwrap();
+        assert!(cached_analysis(&analyzer, &project, &segment).is_some());
+        let second = block_on(analyze(&analyzer, &project, &segment)).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        // Nothing is shared with another analyzer
+        assert!(cached_analysis(&fixture_analyzer(), &project, &segment).is_none());
+    }
+
+    #[test]
+    fn analyze_all_keeps_the_order_of_the_segments() {
+        let analyzer = fixture_analyzer();
+        let project = project();
+        let segments = vec![
+            Segment::new("hello world"),
+            Segment::new("hello there"),
+            Segment::new("hello world"),
+            Segment::new("read"),
+        ];
+        let analyses = block_on(analyze_all(&analyzer, &project, &segments));
+        assert_eq!(analyses.len(), 4);
+        let first = analyses[0].as_ref().unwrap();
+        assert!(Arc::ptr_eq(first, analyses[2].as_ref().unwrap()));
+        let ambiguity = |i: usize| match analyses[i].as_ref().unwrap_err() {
+            AnalysisError::Ambiguity(e) => e.clone(),
+            AnalysisError::Timeout => unreachable!(),
+        };
+        assert_eq!(ambiguity(1).word, "there");
+        assert!(!ambiguity(3).candidates.is_empty());
+    }
+
+    #[test]
+    fn diagnose_locates_every_word() {
+        let analyzer = fixture_analyzer();
+        let words = block_on(diagnose(
+            &analyzer,
+            &project(),
+            &Segment::new("Hello, there!"),
+            None,
+        ));
+        assert_eq!(words.len(), 2);
+        assert_eq!((words[1].start, words[1].end), (7, 12));
+        assert!(matches!(words[0].status, WordStatus::Ok));
+        assert!(matches!(words[1].status, WordStatus::Unavailable));
+    }
+
+    #[test]
+    fn diagnose_word_by_word_without_diagnosis() {
+        let analyzer = FirstProblemOnly(fixture_analyzer());
+        let segment = Segment::new("There, hello read nope");
+        let failure = AmbiguityError {
+            word: "There".to_owned(),
+            candidates: vec![],
+        };
+        let words = block_on(diagnose(&analyzer, &project(), &segment, Some(&failure)));
+        let statuses: Vec<_> = words.into_iter().map(|w| w.status).collect();
+        assert!(matches!(
+            statuses.as_slice(),
+            [
+                WordStatus::Unavailable,
+                WordStatus::Ok,
+                WordStatus::Ambiguous { .. },
+                WordStatus::Unavailable
+            ]
+        ));
+        // The word which made the sentence fail is not analyzed again
+        let key = AnalysisId::from_request(&AnalysisRequest::new(
+            &project(),
+            &Segment {
+                raw: true,
+                ..Segment::new("there")
+            },
+        ));
+        assert!(block_on(get_from_cache(&analyzer, &key)).is_none());
+    }
+
+    #[test]
+    fn coverage_only_suggests_words_the_sources_can_say() {
+        let analyzer = fixture_analyzer();
+        let (_, words) = block_on(coverage(&analyzer, &project(), "hello wordl"));
+        assert!(words[0].suggestions.is_empty());
+        assert_eq!(words[1].suggestions, vec!["word", "world"]);
+    }
+
+    #[test]
+    fn coverage_checks_every_suggestion() {
+        let analyzer = FirstProblemOnly(fixture_analyzer());
+        let (_, words) = block_on(coverage(&analyzer, &project(), "hello wordl"));
+        assert!(matches!(
+            words[1].diagnostic.status,
+            WordStatus::Unavailable
+        ));
+        assert_eq!(words[1].suggestions, vec!["word", "world"]);
     }
 }
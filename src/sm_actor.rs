@@ -1,15 +1,31 @@
+use crate::analyzer::Analyzer;
 use crate::data::PreviewId;
-use crate::data::{Preview, Project, ProjectId, Seed, Segment};
+use crate::data::{
+    AnalysisError, AnalysisId, AnalysisRequest, AnalysisResult, Combo, ComboInfo, Phonem, Preview,
+    Project, ProjectId, SearchHit, SearchQuery, Seed, Segment, WordDiagnostic, WordStatus,
+};
 use crate::downloader::GetVideos;
+use crate::error::RenderError;
 use crate::error::*;
+use crate::export::{Caption, ExportOptions};
 use crate::messages::ServerRequest;
+use crate::render_pool::{JobHandle, JobId};
 use actix::*;
+use futures::StreamExt;
 use rand::{self, rngs::ThreadRng, Rng};
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub type SessionId = usize;
 pub type ClientId = usize;
+/// Readings of the ambiguous words of a segment, by lowercase word
+type Candidates = HashMap<String, Vec<String>>;
+
+/// Delay without any new edit of a segment before its preview is computed
+const PREVIEW_DEBOUNCE: Duration = Duration::from_millis(300);
 
 use crate::sm;
 
@@ -26,86 +42,204 @@ macro_rules! clone_segment {
 }
 
 macro_rules! async_run_preview {
-    ($request:expr, $recipients:expr, $project:expr, $segment:expr, $fut_videos: expr, $preview: expr, $segment_position: expr) => {
+    ($analyzer:expr, $addr:expr, $recipients:expr, $project:expr, $segment:expr, $fut_videos: expr, $segment_position: expr, $job: expr) => {
         async move {
-            broadcast($request, &$recipients).await;
             // Prepare preview and sends it
-            if $preview {
-                let combos = sm::analyze(&$project, &$segment.sentence).await;
-                if let Err(ambiguity) = combos {
-                    let request = ServerRequest::AmbiguityToken {
-                        token: ambiguity.word,
+            let analyzed_sentence = match $segment.markup(&$project) {
+                Ok(analyzed) => analyzed.text,
+                Err(error) => {
+                    let request = ServerRequest::MarkupError {
                         row: $segment_position,
+                        error,
                     };
                     broadcast(request, &$recipients).await;
                     return;
                 }
-                let combos = combos.unwrap();
+            };
+            let (combo_index, combo) = match &$segment.frozen {
+                // Frozen segments are never analyzed again
+                Some(combo) => ($segment.combo_index, combo.clone()),
+                None => {
+                    let combos = match sm::analyze(&*$analyzer, &$project, &$segment).await {
+                        Ok(combos) => combos,
+                        Err(AnalysisError::Timeout) => {
+                            let request = ServerRequest::AnalysisTimeout {
+                                row: $segment_position,
+                            };
+                            broadcast(request, &$recipients).await;
+                            return;
+                        }
+                        Err(AnalysisError::Ambiguity(ambiguity)) => {
+                            let request = ServerRequest::AmbiguityToken {
+                                token: ambiguity.word.clone(),
+                                candidates: ambiguity.candidates.clone(),
+                                row: $segment_position,
+                            };
+                            broadcast(request, &$recipients).await;
+
+                            // Report every problem of the sentence at once
+                            let words =
+                                sm::diagnose(&*$analyzer, &$project, &$segment, Some(&ambiguity))
+                                    .await;
+                            // Kept to check the readings chosen by the users
+                            let readings = |candidates: &[crate::data::Reading]| {
+                                candidates.iter().map(|c| c.reading.clone()).collect()
+                            };
+                            let mut candidates: Candidates = words
+                                .iter()
+                                .filter_map(|w| match &w.status {
+                                    WordStatus::Ambiguous { candidates } => {
+                                        Some((w.word.to_lowercase(), readings(candidates)))
+                                    }
+                                    _ => None,
+                                })
+                                .collect();
+                            if !ambiguity.candidates.is_empty() {
+                                candidates.insert(
+                                    ambiguity.word.to_lowercase(),
+                                    readings(&ambiguity.candidates),
+                                );
+                            }
+                            $addr.do_send(StoreCandidates {
+                                project_name: $project.name.clone(),
+                                segment_position: $segment_position,
+                                sentence: $segment.sentence.clone(),
+                                candidates,
+                            });
+                            let request = ServerRequest::SentenceDiagnostics {
+                                row: $segment_position,
+                                sentence: analyzed_sentence,
+                                words,
+                            };
+                            broadcast(request, &$recipients).await;
+                            return;
+                        }
+                    };
 
-                let videos = $fut_videos.await;
-                if let Err(_) = videos {
-                    // Mailbox is full and we should just ignore this
-                    return;
-                }
-                let videos = videos.unwrap();
+                    // The whole sentence can be said
+                    let words = crate::analyzer::tokenize(&analyzed_sentence)
+                        .into_iter()
+                        .map(|word| WordDiagnostic {
+                            word: word.text,
+                            start: word.start,
+                            end: word.end,
+                            status: WordStatus::Ok,
+                        })
+                        .collect();
+                    let request = ServerRequest::SentenceDiagnostics {
+                        row: $segment_position,
+                        sentence: analyzed_sentence,
+                        words,
+                    };
+                    broadcast(request, &$recipients).await;
 
-                if let Err(_) = videos {
-                    println!("Video downloading is pending, cannot generate the preview yet");
-                    // TODO: We should just ignore and wait
-                    // Maybe send a message to the client to notify that
-                    return;
+                    let (combo_index, combo) = match select_combo(&combos, $segment.combo_index) {
+                        Some(selected) => selected,
+                        // Nothing to preview
+                        None => return,
+                    };
+                    if combo_index != $segment.combo_index {
+                        $addr.do_send(ClampComboIndex {
+                            project_name: $project.name.clone(),
+                            segment_position: $segment_position,
+                            sentence: $segment.sentence.clone(),
+                            combo_count: combos.combos.len(),
+                        });
+                    }
+                    (combo_index, combo.clone())
                 }
-                let videos = videos.unwrap();
+            };
 
-                // TODO: run n first previews
-                let res = crate::renderer::preview(&videos, &combos[$segment.combo_index as usize]);
+            let videos = $fut_videos.await;
+            if let Err(_) = videos {
+                // Mailbox is full and we should just ignore this
+                return;
+            }
+            let videos = videos.unwrap();
 
-                if let Err(_) = res {
-                    println!("Error while generating the preview");
-                    // TODO: We should probably retry
-                    return;
-                }
-                let path = res.unwrap();
+            if let Err(_) = videos {
+                println!("Video downloading is pending, cannot generate the preview yet");
+                // TODO: We should just ignore and wait
+                // Maybe send a message to the client to notify that
+                return;
+            }
+            let videos = videos.unwrap();
 
-                let bytes = async_fs::read(path).await;
-                if let Err(_) = bytes {
-                    println!("Cannot find preview in filesystem");
-                    // TODO: We should probably re-compute the preview
+            // TODO: run n first previews
+            let combo = match $segment.rendered_combo(&combo, &$project) {
+                Ok(combo) => combo,
+                Err(error) => {
+                    let request = ServerRequest::MarkupError {
+                        row: $segment_position,
+                        error,
+                    };
+                    broadcast(request, &$recipients).await;
                     return;
                 }
-                let bytes = bytes.unwrap();
+            };
+            let request = ServerRequest::RenderStarted {
+                id: $job.handle.id,
+                row: Some($segment_position),
+                hash: None,
+            };
+            broadcast(request, &$recipients).await;
+            let res = crate::renderer::preview(videos, combo, $job.handle.clone()).await;
 
-                let decoder = base64::encode(bytes);
-                let data = decoder.to_owned();
-                let r = ServerRequest::Preview {
-                    segment: $segment,
-                    data,
-                };
-                broadcast(r, &$recipients).await;
+            if let Err(RenderError::Cancelled) = res {
+                return;
+            }
+            if let Err(e) = res {
+                println!("Error while generating the preview: {}", e);
+                // TODO: We should probably retry
+                return;
+            }
+            let path = res.unwrap();
+
+            let bytes = async_fs::read(path).await;
+            if let Err(_) = bytes {
+                println!("Cannot find preview in filesystem");
+                // TODO: We should probably re-compute the preview
+                return;
             }
+            let bytes = bytes.unwrap();
+
+            let decoder = base64::encode(bytes);
+            let data = decoder.to_owned();
+            let r = ServerRequest::Preview {
+                segment: Segment {
+                    combo_index,
+                    ..$segment
+                },
+                data,
+            };
+            broadcast(r, &$recipients).await;
         }
     };
 }
 
-macro_rules! send_broadcast_async_preview {
-    ($self:expr, $project_name: expr, $segment_position: expr, $request: expr, $preview: expr) => {{
+macro_rules! send_async_preview {
+    ($self:expr, $ctx:expr, $project_name: expr, $segment_position: expr, $job: expr) => {{
         let recipients = $self.get_all_cloned_recipients_project(&$project_name);
+        let analyzer = $self.analyzer.clone();
+        let addr = $ctx.address();
 
         let segment = clone_segment!($self, $project_name, $segment_position);
         let project = clone_project!($self, $project_name);
+        let job = $job;
 
         let fut_videos = $self.downloader.send(GetVideos {
             yt_ids: project.video_ids.clone(),
         });
 
         async_run_preview!(
-            $request,
+            analyzer,
+            addr,
             recipients,
             project,
             segment,
             fut_videos,
-            $preview,
-            $segment_position
+            $segment_position,
+            job
         )
     }};
 }
@@ -137,6 +271,47 @@ pub struct Disconnect {
     pub id: ClientId,
 }
 
+/// The combo index of a segment is not valid anymore after a re-analysis
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClampComboIndex {
+    pub project_name: ProjectId,
+    pub segment_position: usize,
+    /// Sentence which was analyzed, the segment may have been edited since
+    pub sentence: String,
+    pub combo_count: usize,
+}
+
+/// Readings reported by the analyzer for the ambiguous words of a segment
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StoreCandidates {
+    pub project_name: ProjectId,
+    pub segment_position: usize,
+    /// Sentence which was analyzed, the segment may have been edited since
+    pub sentence: String,
+    pub candidates: Candidates,
+}
+
+/// A render job ended, it cannot be cancelled anymore
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EndRenderJob {
+    pub id: JobId,
+}
+
+/// Render job of a project, unregistered once dropped
+pub struct RenderJobGuard {
+    addr: Addr<SmActor>,
+    pub handle: JobHandle,
+}
+
+impl Drop for RenderJobGuard {
+    fn drop(&mut self) {
+        self.addr.do_send(EndRenderJob { id: self.handle.id });
+    }
+}
+
 /// List of available rooms
 #[derive(Deserialize)]
 pub struct ListProjects;
@@ -152,6 +327,8 @@ pub struct CreateProject {
     pub project_name: ProjectId,
     pub seed: Seed,
     pub urls: Vec<String>,
+    #[serde(default)]
+    pub language: Option<String>,
 }
 impl actix::Message for CreateProject {
     type Result = Result<(), ServerError>;
@@ -190,6 +367,29 @@ impl actix::Message for CreateSegment {
     type Result = Result<(), ServerError>;
 }
 
+/// List the words and phonems the sources of a project can say
+#[derive(Deserialize)]
+pub struct ListVocabulary {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+}
+impl actix::Message for ListVocabulary {
+    type Result = Result<(), ServerError>;
+}
+
+/// Tell which words of a sentence, or a whole script, the sources can say
+#[derive(Deserialize)]
+pub struct CheckCoverage {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub text: String,
+}
+impl actix::Message for CheckCoverage {
+    type Result = Result<(), ServerError>;
+}
+
 /// Modify a segment's sentence
 #[derive(Deserialize)]
 pub struct ModifySegmentSentence {
@@ -228,17 +428,210 @@ impl actix::Message for RemoveSegment {
     type Result = Result<(), ServerError>;
 }
 
+/// Choose the reading of an ambiguous word of a segment
+#[derive(Deserialize)]
+pub struct ResolveAmbiguity {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+    pub word: String,
+    /// `None` forgets the previous choice
+    pub reading: Option<String>,
+}
+impl actix::Message for ResolveAmbiguity {
+    type Result = Result<(), ServerError>;
+}
+
+/// Override the pronunciation of a word in the whole project
+#[derive(Deserialize)]
+pub struct SetPronunciation {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub word: String,
+    /// `None` removes the word from the dictionary
+    pub phonems: Option<Vec<String>>,
+}
+impl actix::Message for SetPronunciation {
+    type Result = Result<(), ServerError>;
+}
+
+/// Change the language of the source videos of a project
+#[derive(Deserialize)]
+pub struct SetLanguage {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    /// `None` lets the analyzer use its default language
+    pub language: Option<String>,
+}
+impl actix::Message for SetLanguage {
+    type Result = Result<(), ServerError>;
+}
+
+/// List the languages supported by the analyzer
+#[derive(Deserialize)]
+pub struct ListLanguages {
+    #[serde(skip)]
+    pub id: ClientId,
+}
+impl actix::Message for ListLanguages {
+    type Result = Result<(), ServerError>;
+}
+
+/// Spell out numbers and symbols of a segment before its analysis, or not
+#[derive(Deserialize)]
+pub struct SetNormalization {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+    pub enabled: bool,
+}
+impl actix::Message for SetNormalization {
+    type Result = Result<(), ServerError>;
+}
+
+/// Keep the chosen combo of a segment, or of every segment of the project if
+/// `segment_position` is `None`, so that it never changes with the analyzer
+#[derive(Deserialize)]
+pub struct FreezeSegments {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: Option<u16>,
+}
+impl actix::Message for FreezeSegments {
+    type Result = Result<(), ServerError>;
+}
+
+/// Drop the frozen combo of a segment, or of every segment of the project if
+/// `segment_position` is `None`, and analyze them again
+#[derive(Deserialize)]
+pub struct UnfreezeSegments {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: Option<u16>,
+}
+impl actix::Message for UnfreezeSegments {
+    type Result = Result<(), ServerError>;
+}
+
+/// Give a segment a new random seed, so that it gets other combos
+#[derive(Deserialize)]
+pub struct RerollSegment {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+}
+impl actix::Message for RerollSegment {
+    type Result = Result<(), ServerError>;
+}
+
+fn default_combos_limit() -> usize {
+    50
+}
+/// Maximum number of combos described in a single answer
+const MAX_COMBOS_LIMIT: usize = 500;
+
+/// Describe a page of the combos of a segment
+#[derive(Deserialize)]
+pub struct ListCombos {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_combos_limit")]
+    pub limit: usize,
+}
+impl actix::Message for ListCombos {
+    type Result = Result<(), ServerError>;
+}
+
+/// List the occurrences of the source videos which can replace a phonem of
+/// the chosen combo of a segment
+#[derive(Deserialize)]
+pub struct ListPhonemAlternatives {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+    pub phonem_index: usize,
+}
+impl actix::Message for ListPhonemAlternatives {
+    type Result = Result<(), ServerError>;
+}
+
+/// Replace a phonem of the chosen combo of a segment
+#[derive(Deserialize)]
+pub struct OverridePhonem {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub segment_position: u16,
+    pub phonem_index: usize,
+    /// `None` restores the phonem of the combo
+    pub phonem: Option<Phonem>,
+}
+impl actix::Message for OverridePhonem {
+    type Result = Result<(), ServerError>;
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+/// Maximum number of search hits sent, and rendered, for a single search
+const MAX_SEARCH_LIMIT: usize = 100;
+
+/// Look for a word or a phonem sequence in the videos of a project
+#[derive(Deserialize)]
+pub struct Search {
+    #[serde(skip)]
+    pub id: ClientId,
+    pub project_name: ProjectId,
+    pub query: SearchQuery,
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+}
+impl actix::Message for Search {
+    type Result = Result<(), ServerError>;
+}
+
 /// Remove a segment
 #[derive(Deserialize)]
 pub struct Export {
     #[serde(skip)]
     pub id: ClientId,
     pub project_name: ProjectId,
+    #[serde(default)]
+    pub options: ExportOptions,
 }
 impl actix::Message for Export {
     type Result = Result<(), ServerError>;
 }
 
+/// Stop a preview or an export being rendered
+#[derive(Deserialize)]
+pub struct CancelRender {
+    #[serde(skip)]
+    pub id: ClientId,
+    #[serde(rename = "id")]
+    pub job: JobId,
+}
+impl actix::Message for CancelRender {
+    type Result = Result<(), ServerError>;
+}
+
+/// Videos were downloaded, the vocabularies they miss can be built
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StoreVocabularies;
+
 /// Load a project
 #[derive(Deserialize)]
 pub struct Load {
@@ -254,16 +647,35 @@ pub struct SmActor {
     editing_sessions: HashMap<ProjectId, HashSet<ClientId>>,
     rng: ThreadRng,
     downloader: actix::Addr<crate::downloader::DownloaderActor>,
+    analyzer: Arc<dyn Analyzer>,
+    /// Pending or running preview of each segment, so that it can be superseded
+    preview_jobs: HashMap<(ProjectId, usize), PreviewJob>,
+    /// Render jobs which can be cancelled, with their project
+    render_jobs: HashMap<JobId, (ProjectId, JobHandle)>,
+    /// Readings of the ambiguous words of each segment, with the sentence
+    /// they were reported for
+    candidates: HashMap<(ProjectId, usize), (String, Candidates)>,
+}
+
+/// Preview of a segment, waiting for its edits to settle or running
+struct PreviewJob {
+    future: SpawnHandle,
+    /// Render job of the preview, once it has started
+    job: Option<JobHandle>,
 }
 
 impl SmActor {
-    pub fn new() -> SmActor {
+    pub fn new(analyzer: Arc<dyn Analyzer>) -> SmActor {
         SmActor {
             sessions: HashMap::new(),
             projects: HashMap::new(),
             editing_sessions: HashMap::new(),
             rng: rand::thread_rng(),
             downloader: crate::downloader::DownloaderActor::new().start(),
+            analyzer,
+            preview_jobs: HashMap::new(),
+            render_jobs: HashMap::new(),
+            candidates: HashMap::new(),
         }
     }
 }
@@ -276,6 +688,19 @@ fn hash_segments(segments: &[Segment]) -> String {
         .join("")
 }
 
+/// Removes the overrides of the segment, returning their phonem indices
+fn take_overrides(segment: &mut Segment) -> Vec<usize> {
+    let indices = segment.overrides.keys().copied().collect();
+    segment.overrides.clear();
+    indices
+}
+
+/// Combo selected by `combo_index`, or the last one if the index is out of bounds
+fn select_combo(analysis: &AnalysisResult, combo_index: u16) -> Option<(u16, &Combo)> {
+    let index = (combo_index as usize).min(analysis.combos.len().checked_sub(1)?);
+    Some((index as u16, &analysis.combos[index]))
+}
+
 // Async function used to send a server request to a list of recipients
 async fn broadcast(request: ServerRequest, recipients: &[Recipient<SmMessage>]) {
     let m = SmMessage::from(&request);
@@ -321,12 +746,19 @@ impl SmActor {
         project_name: ProjectId,
         seed: Seed,
         video_urls: &[String],
+        language: Option<String>,
     ) -> Result<Box<Project>, ServerError> {
         if self.projects.contains_key(&project_name) {
             return Err(ServerError::ProjectAlreadyExists);
         }
+        if let Some(l) = &language {
+            if l.trim().is_empty() {
+                return Err(ServerError::InvalidLanguage);
+            }
+        }
 
-        let project = Box::new(Project::new(&project_name, &seed, video_urls));
+        let mut project = Box::new(Project::new(&project_name, &seed, video_urls));
+        project.language = language;
         self.projects.insert(project_name.clone(), project.clone());
         self.editing_sessions.insert(project_name, HashSet::new());
 
@@ -373,12 +805,16 @@ impl SmActor {
             video_ids,
             name,
             segments,
+            dictionary,
+            language,
         } = &*self.projects[&project_name];
         let request_user_change_server = ServerRequest::ChangeProject {
             seed: (*seed).clone(),
             video_urls: (*video_ids).clone(),
             name: (*name).clone(),
             segments: (*segments).clone(),
+            dictionary: (*dictionary).clone(),
+            language: (*language).clone(),
         };
         let request_notify_join = ServerRequest::UserJoinedProject { user };
 
@@ -430,7 +866,14 @@ impl SmActor {
             Some(s) => s,
             None => return Err(ServerError::SegmentOutOfBounds),
         };
+        if segment.frozen.is_some() {
+            return Err(ServerError::SegmentFrozen);
+        }
         segment.sentence = sentence.clone();
+        // Readings of words which are not in the sentence anymore are useless
+        let words = crate::analyzer::words(&segment.analyzed_sentence(project.language.as_deref()));
+        segment.readings.retain(|word, _| words.contains(word));
+        segment.overrides.clear();
 
         let r = ServerRequest::ChangeSentence {
             row: segment_position as usize,
@@ -440,76 +883,585 @@ impl SmActor {
         Ok(r)
     }
 
+    /// Returns the request and the indices of the overrides removed by the change
+    /// `stored` is the analysis read from the cache for the segment, which may
+    /// have been edited since
     fn modify_segment_combo_index(
         &mut self,
         project_name: ProjectId,
         segment_position: u16,
         index: u16,
-    ) -> Result<ServerRequest, ServerError> {
+        stored: Option<(AnalysisId, Arc<AnalysisResult>)>,
+    ) -> Result<(ServerRequest, Vec<usize>), ServerError> {
         let project = match self.projects.get_mut(&project_name) {
             Some(p) => p,
             None => return Err(ServerError::ProjectDoesNotExist),
         };
 
-        let segment = match project.segments.get_mut(segment_position as usize) {
+        let segment = match project.segments.get(segment_position as usize) {
             Some(s) => s,
             None => return Err(ServerError::SegmentOutOfBounds),
         };
+        if segment.frozen.is_some() && segment.combo_index != index {
+            return Err(ServerError::SegmentFrozen);
+        }
+        let key = AnalysisId::from_request(&AnalysisRequest::new(project, segment));
+        let analysis = match stored {
+            Some((id, analysis)) if id == key => Some(analysis),
+            _ => sm::cached_analysis(&*self.analyzer, project, segment),
+        };
+        // Without analysis yet, the index is checked once the analysis is done
+        if let Some(analysis) = analysis {
+            if index as usize >= analysis.combos.len() {
+                return Err(ServerError::InvalidComboIndex);
+            }
+        }
+
+        let segment = &mut project.segments[segment_position as usize];
+        let cleared = if segment.combo_index != index {
+            take_overrides(segment)
+        } else {
+            vec![]
+        };
         segment.combo_index = index;
 
         let r = ServerRequest::ChangeComboIndex {
             row: segment_position as usize,
             combo_index: index,
         };
-        Ok(r)
+        Ok((r, cleared))
     }
 
-    fn remove_segment(
+    fn resolve_ambiguity(
         &mut self,
         project_name: ProjectId,
         segment_position: u16,
+        word: String,
+        reading: Option<String>,
     ) -> Result<ServerRequest, ServerError> {
         let project = match self.projects.get_mut(&project_name) {
             Some(p) => p,
             None => return Err(ServerError::ProjectDoesNotExist),
         };
 
-        if segment_position as usize >= project.segments.len() {
-            return Err(ServerError::SegmentOutOfBounds);
+        let segment = match project.segments.get_mut(segment_position as usize) {
+            Some(s) => s,
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        if segment.frozen.is_some() {
+            return Err(ServerError::SegmentFrozen);
         }
+        let word = word.to_lowercase();
+        // A choice can be forgotten once the word was removed from the sentence
+        let forgotten = reading.is_none() && segment.readings.contains_key(&word);
+        let occurs = crate::analyzer::tokenize(&segment.sentence)
+            .iter()
+            .any(|w| w.text.to_lowercase() == word);
+        if !occurs && !forgotten {
+            return Err(ServerError::WordNotInSentence);
+        }
+        // The reading must be one of the candidates reported for the sentence
+        if let Some(reading) = &reading {
+            let key = (project_name, segment_position as usize);
+            let valid = match self.candidates.get(&key) {
+                Some((sentence, candidates)) if *sentence == segment.sentence => {
+                    matches!(candidates.get(&word), Some(c) if c.contains(reading))
+                }
+                _ => false,
+            };
+            if !valid {
+                return Err(ServerError::InvalidReading);
+            }
+        }
+        match &reading {
+            Some(reading) => segment.readings.insert(word.clone(), reading.clone()),
+            None => segment.readings.remove(&word),
+        };
+        segment.overrides.clear();
 
-        project.segments.remove(segment_position as usize);
-
-        let r = ServerRequest::RemoveSegment {
+        let r = ServerRequest::ChangeReading {
             row: segment_position as usize,
+            word,
+            reading,
         };
         Ok(r)
     }
-}
-
-impl Actor for SmActor {
-    type Context = Context<Self>;
-}
 
-/// Register new session and assign unique id to this session
-impl Handler<Connect> for SmActor {
-    type Result = usize;
+    /// Returns the request and the rows of the segments using the word
+    fn set_pronunciation(
+        &mut self,
+        project_name: ProjectId,
+        word: String,
+        phonems: Option<Vec<String>>,
+    ) -> Result<(ServerRequest, Vec<usize>), ServerError> {
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+        let word = word.trim().to_lowercase();
+        if word.is_empty() || word.contains(char::is_whitespace) {
+            return Err(ServerError::InvalidPronunciation);
+        }
+        match &phonems {
+            Some(p) if p.is_empty() => return Err(ServerError::InvalidPronunciation),
+            Some(p) => project.dictionary.insert(word.clone(), p.clone()),
+            None => project.dictionary.remove(&word),
+        };
 
-        // register session with random id
-        let id = self.rng.gen::<SessionId>();
-        self.sessions.insert(id, msg.addr);
+        let language = project.language.clone();
+        let rows = project
+            .segments
+            .iter_mut()
+            .enumerate()
+            // Frozen segments keep their combo
+            .filter(|(_, s)| {
+                s.frozen.is_none()
+                    && crate::analyzer::words(&s.analyzed_sentence(language.as_deref()))
+                        .contains(&word)
+            })
+            .map(|(row, s)| {
+                s.overrides.clear();
+                row
+            })
+            .collect();
 
-        // send id back
-        id
+        let r = ServerRequest::ChangePronunciation { word, phonems };
+        Ok((r, rows))
     }
-}
 
-/// Handler for Disconnect message.
-impl Handler<Disconnect> for SmActor {
-    type Result = ();
+    fn set_language(
+        &mut self,
+        project_name: ProjectId,
+        language: Option<String>,
+    ) -> Result<ServerRequest, ServerError> {
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        if let Some(l) = &language {
+            if l.trim().is_empty() {
+                return Err(ServerError::InvalidLanguage);
+            }
+        }
+        project.language = language.clone();
+        // Every combo changes, except the frozen ones
+        for segment in project.segments.iter_mut().filter(|s| s.frozen.is_none()) {
+            segment.overrides.clear();
+        }
+
+        let r = ServerRequest::ChangeLanguage { language };
+        Ok(r)
+    }
+
+    fn set_normalization(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: u16,
+        enabled: bool,
+    ) -> Result<ServerRequest, ServerError> {
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        let segment = match project.segments.get_mut(segment_position as usize) {
+            Some(s) => s,
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        if segment.frozen.is_some() {
+            return Err(ServerError::SegmentFrozen);
+        }
+        segment.raw = !enabled;
+        let words = crate::analyzer::words(&segment.analyzed_sentence(project.language.as_deref()));
+        segment.readings.retain(|word, _| words.contains(word));
+        segment.overrides.clear();
+
+        let r = ServerRequest::ChangeNormalization {
+            row: segment_position as usize,
+            enabled,
+        };
+        Ok(r)
+    }
+
+    fn reroll_segment(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: u16,
+    ) -> Result<ServerRequest, ServerError> {
+        let seed = format!("{:016x}", self.rng.gen::<u64>());
+
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        let segment = match project.segments.get_mut(segment_position as usize) {
+            Some(s) => s,
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        if segment.frozen.is_some() {
+            return Err(ServerError::SegmentFrozen);
+        }
+        segment.seed = Some(seed.clone());
+        segment.overrides.clear();
+
+        let r = ServerRequest::ChangeSegmentSeed {
+            row: segment_position as usize,
+            seed,
+        };
+        Ok(r)
+    }
+
+    /// Freezes the segments whose analysis is cached. A single segment which
+    /// has not been analyzed yet cannot be frozen.
+    /// Rows of the segments targeted by a freeze or an unfreeze
+    fn target_rows(
+        &self,
+        project_name: &str,
+        segment_position: Option<u16>,
+    ) -> Result<Vec<usize>, ServerError> {
+        let project = match self.projects.get(project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        match segment_position {
+            Some(p) if p as usize >= project.segments.len() => Err(ServerError::SegmentOutOfBounds),
+            Some(p) => Ok(vec![p as usize]),
+            None => Ok((0..project.segments.len()).collect()),
+        }
+    }
+
+    /// Freezes the segments whose analysis is in `analyses`, loaded beforehand
+    /// from the cache. Segments edited since then are not frozen.
+    fn freeze_segments(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: Option<u16>,
+        analyses: &HashMap<AnalysisId, Arc<AnalysisResult>>,
+    ) -> Result<ServerRequest, ServerError> {
+        let rows = self.target_rows(&project_name, segment_position)?;
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        let mut frozen = vec![];
+        let mut skipped = vec![];
+        for row in rows {
+            let segment = &project.segments[row];
+            if segment.frozen.is_some() {
+                continue;
+            }
+            let key = AnalysisId::from_request(&AnalysisRequest::new(project, segment));
+            let (index, combo) = match analyses
+                .get(&key)
+                .and_then(|analysis| select_combo(analysis, segment.combo_index))
+            {
+                Some((index, combo)) => (index, combo.clone()),
+                None => {
+                    skipped.push(row);
+                    continue;
+                }
+            };
+            let segment = &mut project.segments[row];
+            // The frozen combo is the one the index designates
+            segment.combo_index = index;
+            segment.frozen = Some(combo);
+            frozen.push(row);
+        }
+        if segment_position.is_some() && !skipped.is_empty() {
+            return Err(ServerError::SegmentNotAnalyzed);
+        }
+
+        let r = ServerRequest::ChangeFrozen {
+            rows: frozen,
+            frozen: true,
+            skipped,
+        };
+        Ok(r)
+    }
+
+    /// Returns the request and the rows of the unfrozen segments
+    fn unfreeze_segments(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: Option<u16>,
+    ) -> Result<(ServerRequest, Vec<usize>), ServerError> {
+        let rows = self.target_rows(&project_name, segment_position)?;
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        let rows: Vec<usize> = rows
+            .into_iter()
+            .filter(|&row| project.segments[row].frozen.take().is_some())
+            .collect();
+
+        let r = ServerRequest::ChangeFrozen {
+            rows: rows.clone(),
+            frozen: false,
+            skipped: vec![],
+        };
+        Ok((r, rows))
+    }
+
+    fn override_phonem(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: u16,
+        phonem_index: usize,
+        phonem: Option<Phonem>,
+        durations: &[f64],
+    ) -> Result<ServerRequest, ServerError> {
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        let segment = match project.segments.get(segment_position as usize) {
+            Some(s) => s,
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        // Alternatives are only listed once the segment has been analyzed
+        let combo = match &segment.frozen {
+            Some(combo) => combo.clone(),
+            None => match sm::cached_analysis(&*self.analyzer, project, segment) {
+                Some(analysis) => match select_combo(&analysis, segment.combo_index) {
+                    Some((_, combo)) => combo.clone(),
+                    None => return Err(ServerError::PhonemOutOfBounds),
+                },
+                None => return Err(ServerError::PhonemOutOfBounds),
+            },
+        };
+        let original = match combo.get(phonem_index) {
+            Some(p) => p,
+            None => return Err(ServerError::PhonemOutOfBounds),
+        };
+        if let Some(p) = &phonem {
+            if !original.is_valid_override(p, durations) {
+                return Err(ServerError::InvalidPhonem);
+            }
+        }
+
+        let segment = &mut project.segments[segment_position as usize];
+        match &phonem {
+            Some(p) => segment.overrides.insert(phonem_index, p.clone()),
+            None => segment.overrides.remove(&phonem_index),
+        };
+
+        let r = ServerRequest::ChangePhonemOverride {
+            row: segment_position as usize,
+            phonem_index,
+            phonem,
+        };
+        Ok(r)
+    }
+
+    fn remove_segment(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: u16,
+    ) -> Result<ServerRequest, ServerError> {
+        let project = match self.projects.get_mut(&project_name) {
+            Some(p) => p,
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+
+        if segment_position as usize >= project.segments.len() {
+            return Err(ServerError::SegmentOutOfBounds);
+        }
+
+        project.segments.remove(segment_position as usize);
+
+        let r = ServerRequest::RemoveSegment {
+            row: segment_position as usize,
+        };
+        Ok(r)
+    }
+}
+
+impl SmActor {
+    /// Computes the preview of a segment once its edits have settled.
+    /// Any previous preview of the segment is cancelled, so that stale results
+    /// never overwrite newer ones.
+    fn schedule_preview(
+        &mut self,
+        project_name: ProjectId,
+        segment_position: usize,
+        ctx: &mut Context<Self>,
+    ) {
+        self.cancel_preview(&project_name, segment_position, ctx);
+
+        let key = (project_name.clone(), segment_position);
+        let future = ctx.run_later(PREVIEW_DEBOUNCE, move |act, ctx| {
+            // The segment may have been removed in the meantime
+            let exists = match act.projects.get(&project_name) {
+                Some(p) => segment_position < p.segments.len(),
+                None => false,
+            };
+            if !exists {
+                act.preview_jobs.remove(&(project_name, segment_position));
+                return;
+            }
+
+            let job = act.start_render_job(&project_name, ctx.address());
+            let job_handle = job.handle.clone();
+            let fut = send_async_preview!(act, ctx, project_name, segment_position, job);
+            let future = ctx.spawn(actix::fut::wrap_future::<_, Self>(fut));
+            let preview = PreviewJob {
+                future,
+                job: Some(job_handle),
+            };
+            act.preview_jobs
+                .insert((project_name, segment_position), preview);
+        });
+        let preview = PreviewJob { future, job: None };
+        self.preview_jobs.insert(key, preview);
+    }
+
+    /// Builds the vocabularies the videos of the project miss, once they are
+    /// all downloaded
+    fn store_vocabularies(
+        &self,
+        project: &Project,
+    ) -> impl Future<Output = Result<(), ServerError>> {
+        let urls: Vec<String> = project.video_ids.iter().map(|id| id.id.clone()).collect();
+        let videos = self.downloader.send(GetVideos {
+            yt_ids: project.video_ids.clone(),
+        });
+        let analyzer = self.analyzer.clone();
+        async move {
+            match videos.await {
+                Ok(Ok(_)) => {}
+                _ => return Err(ServerError::VideosNotReady),
+            }
+            sm::store_vocabularies(&*analyzer, &urls).await;
+            Ok(())
+        }
+    }
+
+    fn cancel_preview(
+        &mut self,
+        project_name: &str,
+        segment_position: usize,
+        ctx: &mut Context<Self>,
+    ) {
+        if let Some(preview) = self
+            .preview_jobs
+            .remove(&(project_name.to_owned(), segment_position))
+        {
+            ctx.cancel_future(preview.future);
+            // The render itself runs on the pool
+            if let Some(job) = preview.job {
+                job.cancel();
+            }
+        }
+    }
+
+    /// Segments at or after `from` moved by `offset` rows. Their running
+    /// previews refer to stale rows, so they are restarted at the new ones.
+    fn shift_previews(
+        &mut self,
+        project_name: &str,
+        from: usize,
+        offset: isize,
+        ctx: &mut Context<Self>,
+    ) {
+        let rows: Vec<_> = self
+            .preview_jobs
+            .keys()
+            .filter(|(name, row)| name == project_name && *row >= from)
+            .map(|(_, row)| *row)
+            .collect();
+        for row in rows.iter() {
+            self.cancel_preview(project_name, *row, ctx);
+        }
+        for row in rows {
+            let new_row = row as isize + offset;
+            if new_row >= 0 {
+                self.schedule_preview(project_name.to_owned(), new_row as usize, ctx);
+            }
+        }
+    }
+
+    /// Registers a render job of the project, cancellable until the guard is dropped
+    fn start_render_job(&mut self, project_name: &str, addr: Addr<Self>) -> RenderJobGuard {
+        let handle = JobHandle::new();
+        self.render_jobs
+            .insert(handle.id, (project_name.to_owned(), handle.clone()));
+        RenderJobGuard { addr, handle }
+    }
+
+    /// Sends a request to every session of the project
+    fn broadcast_project(
+        &self,
+        project_name: &str,
+        request: ServerRequest,
+        ctx: &mut Context<Self>,
+    ) {
+        let recipients = self.get_all_cloned_recipients_project(project_name);
+        let fut = async move {
+            broadcast(request, &recipients).await;
+        };
+        ctx.spawn(actix::fut::wrap_future::<_, Self>(fut));
+    }
+
+    /// Tells every session of the project that the overrides of the row are gone
+    fn broadcast_cleared_overrides(
+        &self,
+        project_name: &str,
+        row: usize,
+        phonem_indices: Vec<usize>,
+        ctx: &mut Context<Self>,
+    ) {
+        for phonem_index in phonem_indices {
+            let request = ServerRequest::ChangePhonemOverride {
+                row,
+                phonem_index,
+                phonem: None,
+            };
+            self.broadcast_project(project_name, request, ctx);
+        }
+    }
+
+    fn cancel_project_previews(&mut self, project_name: &str, ctx: &mut Context<Self>) {
+        let rows: Vec<_> = self
+            .preview_jobs
+            .keys()
+            .filter(|(name, _)| name == project_name)
+            .map(|(_, row)| *row)
+            .collect();
+        for row in rows {
+            self.cancel_preview(project_name, row, ctx);
+        }
+    }
+}
+
+impl Actor for SmActor {
+    type Context = Context<Self>;
+}
+
+/// Register new session and assign unique id to this session
+impl Handler<Connect> for SmActor {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        println!("Someone joined");
+
+        // register session with random id
+        let id = self.rng.gen::<SessionId>();
+        self.sessions.insert(id, msg.addr);
+
+        // send id back
+        id
+    }
+}
+
+/// Handler for Disconnect message.
+impl Handler<Disconnect> for SmActor {
+    type Result = ();
 
     fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
         let r = ServerRequest::UserLeftProject { user: msg.id };
@@ -554,21 +1506,59 @@ impl Handler<ListProjects> for SmActor {
     }
 }
 
+/// Checks that the analyzer reads the language, if any
+async fn check_language(
+    analyzer: Arc<dyn Analyzer>,
+    language: Option<String>,
+) -> Result<Option<String>, ServerError> {
+    match language {
+        Some(l) if !sm::reads_language(&*analyzer, &l).await => Err(ServerError::InvalidLanguage),
+        language => Ok(language),
+    }
+}
+
 // Creates a project and joins it automatically
 impl Handler<CreateProject> for SmActor {
-    type Result = Result<(), ServerError>;
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
+
+    fn handle(&mut self, msg: CreateProject, _ctx: &mut Context<Self>) -> Self::Result {
+        // The language is checked before anything is created
+        let language = msg.language.clone();
+        let check = check_language(self.analyzer.clone(), language);
+        let fut = actix::fut::wrap_future::<_, Self>(check).map(|language, act, ctx| {
+            // The user may have left while the language was checked
+            if !act.sessions.contains_key(&msg.id) {
+                return Err(ServerError::CommunicationError);
+            }
+            act.create_and_join_project(
+                CreateProject {
+                    language: language?,
+                    ..msg
+                },
+                ctx,
+            )
+        });
+        Box::pin(fut)
+    }
+}
 
-    fn handle(&mut self, msg: CreateProject, ctx: &mut Context<Self>) -> Self::Result {
+impl SmActor {
+    fn create_and_join_project(
+        &mut self,
+        msg: CreateProject,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), ServerError> {
         let CreateProject {
             id,
             project_name,
             seed,
             urls,
+            language,
         } = msg;
 
         // Creating a new project
         println!("New project: {} {} {:?}", project_name, seed, urls);
-        let project = self.create_project(project_name.clone(), seed, &urls)?;
+        let project = self.create_project(project_name.clone(), seed, &urls, language)?;
 
         let project_yt_ids = project.video_ids.to_vec();
 
@@ -586,6 +1576,7 @@ impl Handler<CreateProject> for SmActor {
         let all_recipients_except =
             self.get_all_cloned_recipients_project_except(&project_name, id);
 
+        let addr = ctx.address();
         let msg = crate::downloader::DownloadVideos {
             yt_ids: project_yt_ids,
         };
@@ -611,6 +1602,9 @@ impl Handler<CreateProject> for SmActor {
                 println!("Could not find youtube-dl bin");
             } else if let Err(DownloaderError::DownloadFailedError) = dl {
                 println!("Failed to download the videos");
+            } else if dl.is_ok() {
+                // Other projects may have been waiting for these videos
+                addr.do_send(StoreVocabularies);
             }
         };
 
@@ -633,14 +1627,8 @@ impl Handler<DeleteProject> for SmActor {
             Err(e) => return Err(e),
         };
 
-        let recipients = self.get_all_cloned_recipients_project(&project_name);
-
-        let fut = async move {
-            broadcast(request, &recipients).await;
-        };
-
-        let fut = actix::fut::wrap_future::<_, Self>(fut);
-        ctx.spawn(fut);
+        self.cancel_project_previews(&project_name, ctx);
+        self.broadcast_project(&project_name, request, ctx);
 
         Ok(())
     }
@@ -684,20 +1672,37 @@ impl Handler<JoinProject> for SmActor {
         let previews_fut: Vec<_> = project
             .segments
             .iter()
-            .map(|segment| {
+            .enumerate()
+            .map(|(row, segment)| {
                 let project = project.clone();
                 let segment = segment.clone();
+                let analyzer = self.analyzer.clone();
+                let addr = ctx.address();
                 async move {
-                    let combos = sm::analyze(&project, &segment.sentence).await;
-                    if let Err(_) = combos {
-                        return None;
-                    }
-                    let combos = combos.unwrap();
+                    let (combo_index, combo) = match &segment.frozen {
+                        Some(combo) => (segment.combo_index, combo.clone()),
+                        None => {
+                            let combos = sm::analyze(&*analyzer, &project, &segment).await;
+                            if let Err(_) = combos {
+                                return None;
+                            }
+                            let combos = combos.unwrap();
+
+                            let (combo_index, combo) = select_combo(&combos, segment.combo_index)?;
+                            if combo_index != segment.combo_index {
+                                addr.do_send(ClampComboIndex {
+                                    project_name: project.name.clone(),
+                                    segment_position: row,
+                                    sentence: segment.sentence.clone(),
+                                    combo_count: combos.combos.len(),
+                                });
+                            }
+                            (combo_index, combo.clone())
+                        }
+                    };
 
-                    let preview = PreviewId::from_project_sentence(
-                        &project.video_ids,
-                        &combos[segment.combo_index as usize],
-                    );
+                    let combo = segment.rendered_combo(&combo, &project).ok()?;
+                    let preview = PreviewId::from_project_sentence(&project.video_ids, &combo);
                     let path = preview.path();
 
                     let bytes = async_fs::read(path).await;
@@ -711,11 +1716,15 @@ impl Handler<JoinProject> for SmActor {
 
                     Some(Preview {
                         data: data,
-                        segment: segment,
+                        segment: Segment {
+                            combo_index,
+                            ..segment
+                        },
                     })
                 }
             })
             .collect();
+        let analyzer = self.analyzer.clone();
         let fut = async move {
             user_join_project_async(
                 request_joined_users,
@@ -726,6 +1735,15 @@ impl Handler<JoinProject> for SmActor {
             )
             .await;
 
+            // Every uncached sentence is analyzed at once, the previews then
+            // read their analysis from the cache
+            let unfrozen: Vec<_> = project
+                .segments
+                .iter()
+                .filter(|s| s.frozen.is_none())
+                .cloned()
+                .collect();
+            sm::analyze_all(&*analyzer, &project, &unfrozen).await;
             let all_previews = futures::future::join_all(previews_fut).await;
             let all_previews = all_previews
                 .into_iter()
@@ -770,30 +1788,96 @@ impl Handler<CreateSegment> for SmActor {
                 Err(e) => return Err(e),
             };
 
-        let fut = send_broadcast_async_preview!(
-            self,
-            project_name,
-            position as usize,
-            request,
-            !segment_sentence.trim().is_empty()
-        );
+        self.broadcast_project(&project_name, request, ctx);
 
-        let fut = actix::fut::wrap_future::<_, Self>(fut);
-        ctx.spawn(fut);
+        self.shift_previews(&project_name, position as usize, 1, ctx);
+        if !segment_sentence.trim().is_empty() {
+            self.schedule_preview(project_name, position as usize, ctx);
+        }
 
         Ok(())
     }
 }
 
-// Modifies segment sentence
-impl Handler<ModifySegmentSentence> for SmActor {
-    type Result = Result<(), ServerError>;
+// Sends the vocabulary of the project sources to the user
+impl Handler<ListVocabulary> for SmActor {
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
 
-    fn handle(&mut self, msg: ModifySegmentSentence, ctx: &mut Context<Self>) -> Self::Result {
-        let ModifySegmentSentence {
-            project_name,
-            segment_position,
-            new_sentence,
+    fn handle(&mut self, msg: ListVocabulary, _ctx: &mut Context<Self>) -> Self::Result {
+        let ListVocabulary { id, project_name } = msg;
+
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Box::pin(fut::ready(Err(ServerError::ProjectDoesNotExist))),
+        };
+        let user_recipient = self.sessions[&id].clone();
+        let store = self.store_vocabularies(&project);
+
+        let fut = async move {
+            let vocabulary = match sm::vocabulary(&project).await {
+                Some(vocabulary) => vocabulary,
+                // Built now if the videos were not ready before
+                None => {
+                    store.await?;
+                    sm::vocabulary(&project)
+                        .await
+                        .ok_or(ServerError::VocabularyUnavailable)?
+                }
+            };
+            let (words, phonems) = vocabulary;
+            let r = ServerRequest::Vocabulary { words, phonems };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Vocabulary message not properly sent");
+            }
+            Ok(())
+        };
+
+        Box::pin(actix::fut::wrap_future::<_, Self>(fut))
+    }
+}
+
+// Sends the coverage report of a text to the user
+impl Handler<CheckCoverage> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: CheckCoverage, ctx: &mut Context<Self>) -> Self::Result {
+        let CheckCoverage {
+            id,
+            project_name,
+            text,
+        } = msg;
+
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        let user_recipient = self.sessions[&id].clone();
+        let analyzer = self.analyzer.clone();
+
+        let fut = async move {
+            let (text, words) = sm::coverage(&*analyzer, &project, &text).await;
+            let r = ServerRequest::Coverage { text, words };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Coverage message not properly sent");
+            }
+        };
+
+        let fut = actix::fut::wrap_future::<_, Self>(fut);
+        ctx.spawn(fut);
+
+        Ok(())
+    }
+}
+
+// Modifies segment sentence
+impl Handler<ModifySegmentSentence> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: ModifySegmentSentence, ctx: &mut Context<Self>) -> Self::Result {
+        let ModifySegmentSentence {
+            project_name,
+            segment_position,
+            new_sentence,
             ..
         } = msg;
 
@@ -807,15 +1891,8 @@ impl Handler<ModifySegmentSentence> for SmActor {
             Err(e) => return Err(e),
         };
 
-        let fut = send_broadcast_async_preview!(
-            self,
-            project_name,
-            segment_position as usize,
-            request,
-            true
-        );
-        let fut = actix::fut::wrap_future::<_, Self>(fut);
-        ctx.spawn(fut);
+        self.broadcast_project(&project_name, request, ctx);
+        self.schedule_preview(project_name, segment_position as usize, ctx);
 
         Ok(())
     }
@@ -823,9 +1900,9 @@ impl Handler<ModifySegmentSentence> for SmActor {
 
 // Modifies segment combo index
 impl Handler<ModifySegmentComboIndex> for SmActor {
-    type Result = Result<(), ServerError>;
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
 
-    fn handle(&mut self, msg: ModifySegmentComboIndex, ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: ModifySegmentComboIndex, _ctx: &mut Context<Self>) -> Self::Result {
         let ModifySegmentComboIndex {
             project_name,
             segment_position,
@@ -833,22 +1910,499 @@ impl Handler<ModifySegmentComboIndex> for SmActor {
             ..
         } = msg;
 
-        let request = match self.modify_segment_combo_index(
-            project_name.clone(),
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Box::pin(fut::ready(Err(ServerError::ProjectDoesNotExist))),
+        };
+        let segment = match project.segments.get(segment_position as usize) {
+            Some(s) => s.clone(),
+            None => return Box::pin(fut::ready(Err(ServerError::SegmentOutOfBounds))),
+        };
+        let analyzer = self.analyzer.clone();
+
+        // The analysis may not be in memory anymore
+        let load = async move {
+            let analysis = sm::stored_analysis(&*analyzer, &project, &segment).await?;
+            let id = AnalysisId::from_request(&AnalysisRequest::new(&project, &segment));
+            Some((id, analysis))
+        };
+        let fut = actix::fut::wrap_future::<_, Self>(load).map(move |stored, act, ctx| {
+            let (request, cleared) = act.modify_segment_combo_index(
+                project_name.clone(),
+                segment_position,
+                new_combo_index,
+                stored,
+            )?;
+
+            act.broadcast_project(&project_name, request, ctx);
+            act.broadcast_cleared_overrides(&project_name, segment_position as usize, cleared, ctx);
+            act.schedule_preview(project_name, segment_position as usize, ctx);
+
+            Ok(())
+        });
+        Box::pin(fut)
+    }
+}
+
+// Stores the reading chosen for an ambiguous word
+impl Handler<ResolveAmbiguity> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: ResolveAmbiguity, ctx: &mut Context<Self>) -> Self::Result {
+        let ResolveAmbiguity {
+            project_name,
             segment_position,
-            new_combo_index,
-        ) {
-            Ok(r) => r,
-            Err(e) => return Err(e),
+            word,
+            reading,
+            ..
+        } = msg;
+
+        let request =
+            self.resolve_ambiguity(project_name.clone(), segment_position, word, reading)?;
+
+        self.broadcast_project(&project_name, request, ctx);
+        self.schedule_preview(project_name, segment_position as usize, ctx);
+
+        Ok(())
+    }
+}
+
+// Edits the project dictionary
+impl Handler<SetPronunciation> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: SetPronunciation, ctx: &mut Context<Self>) -> Self::Result {
+        let SetPronunciation {
+            project_name,
+            word,
+            phonems,
+            ..
+        } = msg;
+
+        let (request, rows) = self.set_pronunciation(project_name.clone(), word, phonems)?;
+
+        self.broadcast_project(&project_name, request, ctx);
+        // Segments using the word sound differently now
+        for row in rows {
+            self.schedule_preview(project_name.clone(), row, ctx);
+        }
+
+        Ok(())
+    }
+}
+
+// Sends a page of the combo catalogue of a segment to the user
+impl Handler<ListCombos> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: ListCombos, ctx: &mut Context<Self>) -> Self::Result {
+        let ListCombos {
+            id,
+            project_name,
+            segment_position,
+            offset,
+            limit,
+        } = msg;
+
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        let segment = match project.segments.get(segment_position as usize) {
+            Some(s) => s.clone(),
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        let user_recipient = self.sessions[&id].clone();
+        let analyzer = self.analyzer.clone();
+        let row = segment_position as usize;
+
+        let fut = async move {
+            let r = match sm::analyze(&*analyzer, &project, &segment).await {
+                Ok(analysis) => ServerRequest::Combos {
+                    row,
+                    total: analysis.combos.len(),
+                    offset,
+                    combos: analysis
+                        .combos
+                        .iter()
+                        .enumerate()
+                        .skip(offset)
+                        .take(limit.min(MAX_COMBOS_LIMIT))
+                        .map(|(i, combo)| ComboInfo::new(i, combo, analysis.score(i)))
+                        .collect(),
+                },
+                Err(AnalysisError::Ambiguity(ambiguity)) => ServerRequest::AmbiguityToken {
+                    row,
+                    token: ambiguity.word,
+                    candidates: ambiguity.candidates,
+                },
+                Err(AnalysisError::Timeout) => ServerRequest::AnalysisTimeout { row },
+            };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Combos message not properly sent");
+            }
+        };
+
+        let fut = actix::fut::wrap_future::<_, Self>(fut);
+        ctx.spawn(fut);
+
+        Ok(())
+    }
+}
+
+// Sends the possible replacements of a phonem to the user
+impl Handler<ListPhonemAlternatives> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: ListPhonemAlternatives, ctx: &mut Context<Self>) -> Self::Result {
+        let ListPhonemAlternatives {
+            id,
+            project_name,
+            segment_position,
+            phonem_index,
+        } = msg;
+
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        let segment = match project.segments.get(segment_position as usize) {
+            Some(s) => s.clone(),
+            None => return Err(ServerError::SegmentOutOfBounds),
+        };
+        let user_recipient = self.sessions[&id].clone();
+        let analyzer = self.analyzer.clone();
+        let row = segment_position as usize;
+
+        let fut = async move {
+            let original = match &segment.frozen {
+                Some(combo) => combo.get(phonem_index).cloned(),
+                None => match sm::analyze(&*analyzer, &project, &segment).await {
+                    Ok(analysis) => select_combo(&analysis, segment.combo_index)
+                        .and_then(|(_, combo)| combo.get(phonem_index).cloned()),
+                    Err(_) => None,
+                },
+            };
+            let original = match original {
+                Some(p) => p,
+                None => return,
+            };
+            let alternatives = match &original.label {
+                Some(label) => sm::occurrences(&*analyzer, &project, label)
+                    .await
+                    .into_iter()
+                    .filter(|p| *p != original)
+                    .collect(),
+                // Unknown phonem, nothing can replace it
+                None => vec![],
+            };
+
+            let r = ServerRequest::PhonemAlternatives {
+                row,
+                phonem_index,
+                original,
+                alternatives,
+            };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Phonem alternatives message not properly sent");
+            }
+        };
+
+        let fut = actix::fut::wrap_future::<_, Self>(fut);
+        ctx.spawn(fut);
+
+        Ok(())
+    }
+}
+
+// Changes the language of a project and recomputes its previews
+impl Handler<SetLanguage> for SmActor {
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
+
+    fn handle(&mut self, msg: SetLanguage, _ctx: &mut Context<Self>) -> Self::Result {
+        let SetLanguage {
+            project_name,
+            language,
+            ..
+        } = msg;
+
+        let check = check_language(self.analyzer.clone(), language);
+        let fut = actix::fut::wrap_future::<_, Self>(check).map(move |language, act, ctx| {
+            let request = act.set_language(project_name.clone(), language?)?;
+
+            act.broadcast_project(&project_name, request, ctx);
+            for row in 0..act.projects[&project_name].segments.len() {
+                act.schedule_preview(project_name.clone(), row, ctx);
+            }
+
+            Ok(())
+        });
+        Box::pin(fut)
+    }
+}
+
+// Sends the languages supported by the analyzer to the user
+impl Handler<ListLanguages> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: ListLanguages, ctx: &mut Context<Self>) -> Self::Result {
+        let user_recipient = self.sessions[&msg.id].clone();
+        let analyzer = self.analyzer.clone();
+
+        let fut = async move {
+            let languages = sm::languages(&*analyzer).await;
+            let r = ServerRequest::Languages { languages };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Languages message not properly sent");
+            }
+        };
+
+        let fut = actix::fut::wrap_future::<_, Self>(fut);
+        ctx.spawn(fut);
+
+        Ok(())
+    }
+}
+
+// Turns the normalization of a segment on or off
+impl Handler<SetNormalization> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: SetNormalization, ctx: &mut Context<Self>) -> Self::Result {
+        let SetNormalization {
+            project_name,
+            segment_position,
+            enabled,
+            ..
+        } = msg;
+
+        let request = self.set_normalization(project_name.clone(), segment_position, enabled)?;
+
+        self.broadcast_project(&project_name, request, ctx);
+        self.schedule_preview(project_name, segment_position as usize, ctx);
+
+        Ok(())
+    }
+}
+
+// Freezes the chosen combo of segments
+impl Handler<FreezeSegments> for SmActor {
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
+
+    fn handle(&mut self, msg: FreezeSegments, _ctx: &mut Context<Self>) -> Self::Result {
+        let FreezeSegments {
+            project_name,
+            segment_position,
+            ..
+        } = msg;
+
+        let rows = match self.target_rows(&project_name, segment_position) {
+            Ok(rows) => rows,
+            Err(e) => return Box::pin(fut::ready(Err(e))),
+        };
+        let project = (*self.projects[&project_name]).clone();
+        let analyzer = self.analyzer.clone();
+
+        // Analyses which are not in memory anymore are read from the disk
+        let load = async move {
+            let mut analyses = HashMap::new();
+            for row in rows {
+                let segment = &project.segments[row];
+                if segment.frozen.is_some() {
+                    continue;
+                }
+                if let Some(analysis) = sm::stored_analysis(&*analyzer, &project, segment).await {
+                    let request = AnalysisRequest::new(&project, segment);
+                    analyses.insert(AnalysisId::from_request(&request), analysis);
+                }
+            }
+            analyses
+        };
+        let fut = actix::fut::wrap_future::<_, Self>(load).map(move |analyses, act, ctx| {
+            let request = act.freeze_segments(project_name.clone(), segment_position, &analyses)?;
+
+            // The previews do not change
+            act.broadcast_project(&project_name, request, ctx);
+
+            Ok(())
+        });
+        Box::pin(fut)
+    }
+}
+
+// Unfreezes segments and analyzes them again
+impl Handler<UnfreezeSegments> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: UnfreezeSegments, ctx: &mut Context<Self>) -> Self::Result {
+        let UnfreezeSegments {
+            project_name,
+            segment_position,
+            ..
+        } = msg;
+
+        let (request, rows) = self.unfreeze_segments(project_name.clone(), segment_position)?;
+
+        self.broadcast_project(&project_name, request, ctx);
+        for row in rows {
+            self.schedule_preview(project_name.clone(), row, ctx);
+        }
+
+        Ok(())
+    }
+}
+
+// Picks a new seed for a segment
+impl Handler<RerollSegment> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: RerollSegment, ctx: &mut Context<Self>) -> Self::Result {
+        let RerollSegment {
+            project_name,
+            segment_position,
+            ..
+        } = msg;
+
+        let request = self.reroll_segment(project_name.clone(), segment_position)?;
+
+        self.broadcast_project(&project_name, request, ctx);
+        self.schedule_preview(project_name, segment_position as usize, ctx);
+
+        Ok(())
+    }
+}
+
+// Replaces a phonem of the chosen combo
+impl Handler<OverridePhonem> for SmActor {
+    type Result = ResponseActFuture<Self, Result<(), ServerError>>;
+
+    fn handle(&mut self, msg: OverridePhonem, _ctx: &mut Context<Self>) -> Self::Result {
+        let OverridePhonem {
+            project_name,
+            segment_position,
+            phonem_index,
+            phonem,
+            ..
+        } = msg;
+
+        let video_ids = match self.projects.get(&project_name) {
+            Some(p) => p.video_ids.clone(),
+            None => return Box::pin(fut::ready(Err(ServerError::ProjectDoesNotExist))),
         };
+        // Overrides must stay within their source video, whose duration is
+        // only known once it is downloaded
+        let fut_videos = self.downloader.send(GetVideos { yt_ids: video_ids });
+        let fut = actix::fut::wrap_future::<_, Self>(fut_videos).map(move |videos, act, ctx| {
+            let durations: Vec<f64> = match videos {
+                Ok(Ok(videos)) => videos.iter().map(|v| v.duration()).collect(),
+                // Removing an override needs no duration
+                _ if phonem.is_none() => vec![],
+                _ => return Err(ServerError::VideosNotReady),
+            };
+            let request = act.override_phonem(
+                project_name.clone(),
+                segment_position,
+                phonem_index,
+                phonem,
+                &durations,
+            )?;
+
+            act.broadcast_project(&project_name, request, ctx);
+            act.schedule_preview(project_name, segment_position as usize, ctx);
+
+            Ok(())
+        });
+        Box::pin(fut)
+    }
+}
 
-        let fut = send_broadcast_async_preview!(
-            self,
+// Sends the occurrences of a word or phonem sequence, then a clip of each one
+impl Handler<Search> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: Search, ctx: &mut Context<Self>) -> Self::Result {
+        let Search {
+            id,
             project_name,
-            segment_position as usize,
-            request,
-            true
-        );
+            query,
+            limit,
+        } = msg;
+
+        let project = match self.projects.get(&project_name) {
+            Some(p) => (**p).clone(),
+            None => return Err(ServerError::ProjectDoesNotExist),
+        };
+        let user_recipient = self.sessions[&id].clone();
+        let analyzer = self.analyzer.clone();
+        let job = self.start_render_job(&project_name, ctx.address());
+
+        let fut_videos = self.downloader.send(GetVideos {
+            yt_ids: project.video_ids.clone(),
+        });
+
+        let fut = async move {
+            let hits = sm::search(&*analyzer, &project, &query).await;
+            let total = hits.len();
+            let hits: Vec<_> = hits
+                .into_iter()
+                .filter_map(SearchHit::new)
+                .take(limit.min(MAX_SEARCH_LIMIT))
+                .collect();
+
+            let r = ServerRequest::SearchResults {
+                id: job.handle.id,
+                query: query.clone(),
+                total,
+                hits: hits.clone(),
+            };
+            if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                println!("Search results message not properly sent");
+                return;
+            }
+
+            let videos = match fut_videos.await {
+                Ok(Ok(videos)) => videos,
+                _ => {
+                    println!("Video downloading is pending, cannot render the search clips yet");
+                    return;
+                }
+            };
+
+            // Clips are sent one by one, so that the first ones can be played early
+            for (index, hit) in hits.iter().enumerate() {
+                let clip = crate::renderer::search_clip(
+                    videos.clone(),
+                    hit.phonems.clone(),
+                    job.handle.clone(),
+                );
+                let path = match clip.await {
+                    Ok(path) => path,
+                    Err(RenderError::Cancelled) => return,
+                    Err(e) => {
+                        println!("Error while generating a search clip: {}", e);
+                        continue;
+                    }
+                };
+                let bytes = match async_fs::read(path).await {
+                    Ok(bytes) => bytes,
+                    Err(_) => {
+                        println!("Cannot find search clip in filesystem");
+                        continue;
+                    }
+                };
+
+                let r = ServerRequest::SearchClip {
+                    query: query.clone(),
+                    index,
+                    data: base64::encode(bytes),
+                };
+                if user_recipient.send(SmMessage::from(&r)).await.is_err() {
+                    // The user left
+                    return;
+                }
+            }
+        };
+
         let fut = actix::fut::wrap_future::<_, Self>(fut);
         ctx.spawn(fut);
 
@@ -873,16 +2427,11 @@ impl Handler<RemoveSegment> for SmActor {
             Err(e) => return Err(e),
         };
 
-        // Get the list of the sessions linked to the project
-        let recipients = self.get_all_cloned_recipients_project(&project_name);
+        // Send the notification to all involved sessions
+        self.broadcast_project(&project_name, request, ctx);
 
-        let fut = async move {
-            // Send the notification to all involved sessions
-            broadcast(request, &recipients).await;
-        };
-
-        let fut = actix::fut::wrap_future::<_, Self>(fut);
-        ctx.spawn(fut);
+        self.cancel_preview(&project_name, segment_position as usize, ctx);
+        self.shift_previews(&project_name, segment_position as usize + 1, -1, ctx);
 
         Ok(())
     }
@@ -892,46 +2441,109 @@ impl Handler<Export> for SmActor {
     type Result = Result<(), ServerError>;
 
     fn handle(&mut self, msg: Export, ctx: &mut Context<Self>) -> Self::Result {
-        let Export { project_name, .. } = msg;
+        let Export {
+            project_name,
+            options,
+            ..
+        } = msg;
+        // Checked before anything is analyzed or rendered
+        options.validate().map_err(ServerError::UnsupportedExport)?;
 
         // Get the list of the sessions linked to the project
         let recipients = self.get_all_cloned_recipients_project(&project_name);
 
         let project = clone_project!(self, project_name);
+        let analyzer = self.analyzer.clone();
+        let addr = ctx.address();
 
         let fut_videos = self.downloader.send(GetVideos {
             yt_ids: project.video_ids.clone(),
         });
+        let job = self.start_render_job(&project_name, addr.clone());
 
         let fut = async move {
             // Prepare preview and sends it
-            let analysis = project
+            // Frozen segments are rendered without being analyzed
+            let unfrozen: Vec<_> = project
                 .segments
                 .iter()
-                .map(|s| sm::analyze(&project, &s.sentence));
-            let res: Vec<_> = futures::future::join_all(analysis).await;
-            // Throw away all failed analysis (because of ambiguities) and keep all the others
-            let res: Vec<_> = res
-                .iter()
-                .filter(|c| c.as_ref().is_ok())
-                .map(|c| c.as_ref().unwrap())
+                .filter(|s| s.frozen.is_none())
+                .cloned()
                 .collect();
-
-            let combos: Vec<_> = res
+            let mut res = sm::analyze_all(&*analyzer, &project, &unfrozen)
+                .await
+                .into_iter();
+            // Throw away all failed analysis (because of ambiguities or invalid
+            // markup) and keep all the others. The sessions are told which.
+            let mut skipped = vec![];
+            let segments: Vec<_> = project
+                .segments
                 .iter()
                 .enumerate()
-                .map(|(i, combos)| {
-                    combos[project.segments[i as usize].combo_index as usize]
-                        .iter()
-                        .map(|p| (*p).clone())
+                .filter_map(|(row, segment)| {
+                    let combo = match &segment.frozen {
+                        Some(combo) => combo.clone(),
+                        None => {
+                            let analysis = res.next().and_then(Result::ok);
+                            let selected = analysis
+                                .as_ref()
+                                .and_then(|a| select_combo(a, segment.combo_index));
+                            let (analysis, (combo_index, combo)) = match (&analysis, selected) {
+                                (Some(analysis), Some(selected)) => (analysis, selected),
+                                // Ambiguous, or without any combo
+                                _ => {
+                                    skipped.push(row);
+                                    return None;
+                                }
+                            };
+                            if combo_index != segment.combo_index {
+                                addr.do_send(ClampComboIndex {
+                                    project_name: project.name.clone(),
+                                    segment_position: row,
+                                    sentence: segment.sentence.clone(),
+                                    combo_count: analysis.combos.len(),
+                                });
+                            }
+                            combo.clone()
+                        }
+                    };
+                    match segment.rendered_combo(&combo, &project) {
+                        Ok(combo) => Some((segment, combo)),
+                        Err(_) => {
+                            skipped.push(row);
+                            None
+                        }
+                    }
                 })
-                .flatten()
                 .collect();
+            let with_captions = matches!(&options.animation, Some(a) if a.captions);
+            let captions: Vec<_> = if with_captions {
+                segments
+                    .iter()
+                    .map(|(segment, combo)| Caption {
+                        // The sentence as it was typed, without its markup
+                        text: crate::markup::analyze(&segment.sentence, true, None, None)
+                            .map(|m| m.text)
+                            .unwrap_or_default(),
+                        phonems: combo.len(),
+                    })
+                    .collect()
+            } else {
+                vec![]
+            };
+            let combos: Vec<_> = segments.into_iter().flat_map(|(_, combo)| combo).collect();
+
+            let hash = hash_segments(&project.segments);
+            let fail = |error: &str| ServerRequest::RenderFailed {
+                hash: hash.clone(),
+                error: error.to_owned(),
+            };
 
             let video_res = fut_videos.await;
             if let Err(_) = video_res {
                 println!("Mailbox full. Cannot export");
                 // TODO: mailbox full. What should we do ?
+                broadcast(fail("The server is busy"), &recipients).await;
                 return;
             }
             let video_res = video_res.unwrap();
@@ -939,15 +2551,49 @@ impl Handler<Export> for SmActor {
             if let Err(_) = video_res {
                 println!("Video downloading is pending, cannot generate the preview yet");
                 // TODO: We should just ignore and wait
-                // Maybe send a message to the client to notify that
+                broadcast(fail("The source videos are not ready yet"), &recipients).await;
                 return;
             }
             let videos = video_res.unwrap();
 
-            let rendering = crate::renderer::render(&videos, &combos);
-            if let Err(_) = rendering {
-                println!("Error while generating the rendered video");
-                // TODO: We should notify the user
+            let request = ServerRequest::RenderStarted {
+                id: job.handle.id,
+                row: None,
+                hash: Some(hash.clone()),
+            };
+            broadcast(request, &recipients).await;
+
+            // Progress is reported from the render thread, and forwarded
+            // until the rendering ends
+            let (progress_sender, mut progress) = futures::channel::mpsc::unbounded();
+            let rendering = crate::renderer::render(
+                videos,
+                combos,
+                options.clone(),
+                captions,
+                job.handle.clone(),
+                move |p| {
+                    let _ = progress_sender.unbounded_send(p);
+                },
+            );
+            let forward = async {
+                while let Some(p) = progress.next().await {
+                    let r = ServerRequest::RenderProgress {
+                        hash: hash.clone(),
+                        percent: p.percent,
+                        eta: p.eta,
+                    };
+                    broadcast(r, &recipients).await;
+                }
+            };
+            let (rendering, ()) = futures::future::join(rendering, forward).await;
+            // The sessions have been told when it was cancelled
+            if let Err(RenderError::Cancelled) = rendering {
+                return;
+            }
+            if let Err(e) = rendering {
+                println!("Error while generating the rendered video: {}", e);
+                broadcast(fail(&e.to_string()), &recipients).await;
                 return;
             }
             let path = rendering.unwrap();
@@ -955,15 +2601,19 @@ impl Handler<Export> for SmActor {
             let bytes = async_fs::read(path).await;
             if let Err(_) = bytes {
                 println!("Cannot find rendered video in filesystem");
-                // TODO: We should notify the user
+                broadcast(fail("The rendered video is missing"), &recipients).await;
                 return;
             }
             let bytes = bytes.unwrap();
 
             let decoder = base64::encode(bytes);
             let data = decoder.to_owned();
-            let hash = hash_segments(&project.segments);
-            let r = ServerRequest::RenderResult { hash, data };
+            let r = ServerRequest::RenderResult {
+                hash,
+                data,
+                mime_type: options.mime_type().to_owned(),
+                skipped,
+            };
             broadcast(r, &recipients).await;
         };
 
@@ -974,6 +2624,134 @@ impl Handler<Export> for SmActor {
     }
 }
 
+// Cancels a render job of a project the user edits
+impl Handler<CancelRender> for SmActor {
+    type Result = Result<(), ServerError>;
+
+    fn handle(&mut self, msg: CancelRender, ctx: &mut Context<Self>) -> Self::Result {
+        let CancelRender { id, job } = msg;
+
+        let (project_name, handle) = match self.render_jobs.get(&job) {
+            Some((project_name, handle)) => (project_name.clone(), handle.clone()),
+            None => return Err(ServerError::RenderJobDoesNotExist),
+        };
+        let is_editing = match self.editing_sessions.get(&project_name) {
+            Some(sessions) => sessions.contains(&id),
+            None => false,
+        };
+        if !is_editing {
+            return Err(ServerError::RenderJobDoesNotExist);
+        }
+
+        handle.cancel();
+        self.render_jobs.remove(&job);
+
+        let request = ServerRequest::RenderCancelled { id: job };
+        self.broadcast_project(&project_name, request, ctx);
+
+        Ok(())
+    }
+}
+
+// Forgets a render job which ended
+impl Handler<EndRenderJob> for SmActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndRenderJob, _: &mut Context<Self>) {
+        self.render_jobs.remove(&msg.id);
+        // A finished preview is neither superseded nor moved anymore
+        self.preview_jobs
+            .retain(|_, preview| !matches!(&preview.job, Some(job) if job.id == msg.id));
+    }
+}
+
+// Keeps the readings the users can choose from
+impl Handler<StoreCandidates> for SmActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreCandidates, _ctx: &mut Context<Self>) {
+        let StoreCandidates {
+            project_name,
+            segment_position,
+            sentence,
+            candidates,
+        } = msg;
+
+        let current = self
+            .projects
+            .get(&project_name)
+            .and_then(|p| p.segments.get(segment_position));
+        // The segment has been edited since it was analyzed
+        if !matches!(current, Some(s) if s.sentence == sentence) {
+            return;
+        }
+        // The words whose reading was chosen are not ambiguous anymore, their
+        // candidates are kept until the sentence changes
+        let stored = self
+            .candidates
+            .entry((project_name, segment_position))
+            .or_insert_with(|| (sentence.clone(), HashMap::new()));
+        if stored.0 != sentence {
+            *stored = (sentence, HashMap::new());
+        }
+        stored.1.extend(candidates);
+    }
+}
+
+// Builds the vocabularies of the projects whose videos are all downloaded
+impl Handler<StoreVocabularies> for SmActor {
+    type Result = ();
+
+    fn handle(&mut self, _: StoreVocabularies, ctx: &mut Context<Self>) {
+        for project in self.projects.values() {
+            let store = self.store_vocabularies(project);
+            let fut = async move {
+                store.await.ok();
+            };
+            ctx.spawn(actix::fut::wrap_future::<_, Self>(fut));
+        }
+    }
+}
+
+// Fixes a combo index which became invalid
+impl Handler<ClampComboIndex> for SmActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClampComboIndex, ctx: &mut Context<Self>) {
+        let ClampComboIndex {
+            project_name,
+            segment_position,
+            sentence,
+            combo_count,
+        } = msg;
+
+        let segment = match self.projects.get_mut(&project_name) {
+            Some(p) => match p.segments.get_mut(segment_position) {
+                Some(s) => s,
+                None => return,
+            },
+            None => return,
+        };
+        // The segment has been edited or frozen since it was analyzed
+        if segment.sentence != sentence
+            || segment.frozen.is_some()
+            || (segment.combo_index as usize) < combo_count
+        {
+            return;
+        }
+        let combo_index = combo_count.saturating_sub(1) as u16;
+        segment.combo_index = combo_index;
+        let cleared = take_overrides(segment);
+
+        let request = ServerRequest::ChangeComboIndex {
+            row: segment_position,
+            combo_index,
+        };
+        self.broadcast_project(&project_name, request, ctx);
+        self.broadcast_cleared_overrides(&project_name, segment_position, cleared, ctx);
+    }
+}
+
 // Load a project
 impl Handler<Load> for SmActor {
     type Result = Result<(), ServerError>;
@@ -984,6 +2762,8 @@ impl Handler<Load> for SmActor {
         }
         self.projects
             .insert(msg.project.name.clone(), Box::new(msg.project.clone()));
+        self.editing_sessions
+            .insert(msg.project.name.clone(), HashSet::new());
 
         let new_project_request = ServerRequest::NewProject {
             project: msg.project,
@@ -999,3 +2779,326 @@ impl Handler<Load> for SmActor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::tests::fixture_analyzer;
+    use futures::channel::mpsc;
+
+    /// Session forwarding what it receives to the test
+    struct Client(mpsc::UnboundedSender<serde_json::Value>);
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<SmMessage> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: SmMessage, _: &mut Context<Self>) {
+            let _ = self.0.unbounded_send(serde_json::from_str(&msg.0).unwrap());
+        }
+    }
+
+    /// Server with a project named "test", and a session connected to it
+    async fn connect() -> (
+        Addr<SmActor>,
+        ClientId,
+        mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        connect_with(Arc::new(fixture_analyzer())).await
+    }
+
+    async fn connect_with(
+        analyzer: Arc<dyn Analyzer>,
+    ) -> (
+        Addr<SmActor>,
+        ClientId,
+        mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        let server = SmActor::new(analyzer).start();
+        let (sender, mut received) = mpsc::unbounded();
+        let client = Client(sender).start();
+        let id = server
+            .send(Connect {
+                addr: client.recipient(),
+            })
+            .await
+            .unwrap();
+        let project = Project::new("test", "seed", &["a".to_owned(), "b".to_owned()]);
+        server.send(Load { project }).await.unwrap().unwrap();
+        // Every session is told about the new project
+        let message = received.next().await.unwrap();
+        assert!(message.get("NEW_PROJECT").is_some());
+        (server, id, received)
+    }
+
+    #[actix_rt::test]
+    async fn coverage_is_sent_to_the_session_asking_for_it() {
+        let (server, id, mut received) = connect().await;
+        let request = CheckCoverage {
+            id,
+            project_name: "test".to_owned(),
+            text: "hello wordl".to_owned(),
+        };
+        server.send(request).await.unwrap().unwrap();
+
+        let message = received.next().await.unwrap();
+        let coverage = &message["COVERAGE"];
+        assert_eq!(coverage["text"], "hello wordl");
+        assert_eq!(coverage["words"][1]["status"], "unavailable");
+        assert_eq!(coverage["words"][1]["suggestions"][0], "word");
+    }
+
+    /// Rows of the sentence diagnostics received until nothing comes for a while
+    async fn diagnosed_rows(received: &mut mpsc::UnboundedReceiver<serde_json::Value>) -> Vec<u64> {
+        let mut rows = vec![];
+        let wait = PREVIEW_DEBOUNCE * 2;
+        while let Ok(Some(message)) = tokio::time::timeout(wait, received.next()).await {
+            if let Some(row) = message["SENTENCE_DIAGNOSTICS"]["row"].as_u64() {
+                rows.push(row);
+            }
+        }
+        rows
+    }
+
+    #[actix_rt::test]
+    async fn finished_previews_are_not_restarted() {
+        let (server, id, mut received) = connect().await;
+        let join = JoinProject {
+            id,
+            project_name: "test".to_owned(),
+        };
+        server.send(join).await.unwrap().unwrap();
+        let create = |sentence: &str| CreateSegment {
+            id,
+            project_name: "test".to_owned(),
+            segment_sentence: sentence.to_owned(),
+            position: 0,
+        };
+        server.send(create("hello world")).await.unwrap().unwrap();
+        assert_eq!(diagnosed_rows(&mut received).await, vec![0]);
+
+        // Only the new row is previewed, the moved one is done already
+        server.send(create("world")).await.unwrap().unwrap();
+        assert_eq!(diagnosed_rows(&mut received).await, vec![0]);
+    }
+
+    /// Analyzer which never answers in time
+    struct TimingOut(crate::analyzer::MockAnalyzer);
+
+    impl Analyzer for TimingOut {
+        fn analyze(&self, _: crate::data::AnalysisRequest) -> crate::analyzer::AnalysisFuture {
+            Box::pin(async { Err(AnalysisError::Timeout) })
+        }
+
+        fn cache(&self) -> &std::sync::Mutex<crate::cache::AnalysisCache> {
+            self.0.cache()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn timed_out_analyses_are_reported() {
+        let analyzer = Arc::new(TimingOut(fixture_analyzer()));
+        let (server, id, mut received) = connect_with(analyzer).await;
+        let join = JoinProject {
+            id,
+            project_name: "test".to_owned(),
+        };
+        server.send(join).await.unwrap().unwrap();
+        let create = CreateSegment {
+            id,
+            project_name: "test".to_owned(),
+            segment_sentence: "hello world".to_owned(),
+            position: 0,
+        };
+        server.send(create).await.unwrap().unwrap();
+
+        let wait = PREVIEW_DEBOUNCE * 2;
+        let mut timed_out = false;
+        while let Ok(Some(message)) = tokio::time::timeout(wait, received.next()).await {
+            timed_out |= message["ANALYSIS_TIMEOUT"]["row"] == 0;
+        }
+        assert!(timed_out);
+    }
+
+    #[actix_rt::test]
+    async fn frozen_segments_must_be_unfrozen_first() {
+        let (server, id, _) = connect().await;
+        let mut project = Project::new("frozen", "seed", &["a".to_owned()]);
+        project.segments.push(Segment {
+            frozen: Some(vec![]),
+            ..Segment::new("hello")
+        });
+        server.send(Load { project }).await.unwrap().unwrap();
+        let modify = || ModifySegmentSentence {
+            id,
+            project_name: "frozen".to_owned(),
+            segment_position: 0,
+            new_sentence: "world".to_owned(),
+        };
+        let res = server.send(modify()).await.unwrap();
+        assert!(matches!(res, Err(ServerError::SegmentFrozen)));
+        let reroll = RerollSegment {
+            id,
+            project_name: "frozen".to_owned(),
+            segment_position: 0,
+        };
+        let res = server.send(reroll).await.unwrap();
+        assert!(matches!(res, Err(ServerError::SegmentFrozen)));
+
+        let unfreeze = UnfreezeSegments {
+            id,
+            project_name: "frozen".to_owned(),
+            segment_position: Some(0),
+        };
+        server.send(unfreeze).await.unwrap().unwrap();
+        server.send(modify()).await.unwrap().unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn only_languages_of_the_analyzer_are_accepted() {
+        let (server, id, _) = connect().await;
+        let set_language = |language: &str| SetLanguage {
+            id,
+            project_name: "test".to_owned(),
+            language: Some(language.to_owned()),
+        };
+        let res = server.send(set_language("xx")).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidLanguage)));
+        server.send(set_language("fr")).await.unwrap().unwrap();
+        server.send(set_language("en")).await.unwrap().unwrap();
+
+        // Rejected before anything is created or downloaded
+        let create = CreateProject {
+            id,
+            project_name: "other".to_owned(),
+            seed: "seed".to_owned(),
+            urls: vec!["a".to_owned()],
+            language: Some("xx".to_owned()),
+        };
+        let res = server.send(create).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidLanguage)));
+    }
+
+    #[actix_rt::test]
+    async fn readings_must_be_candidates_of_the_word() {
+        let (server, id, mut received) = connect().await;
+        let join = JoinProject {
+            id,
+            project_name: "test".to_owned(),
+        };
+        server.send(join).await.unwrap().unwrap();
+        let create = CreateSegment {
+            id,
+            project_name: "test".to_owned(),
+            segment_sentence: "read it".to_owned(),
+            position: 0,
+        };
+        server.send(create).await.unwrap().unwrap();
+        let resolve = |word: &str, reading: &str| ResolveAmbiguity {
+            id,
+            project_name: "test".to_owned(),
+            segment_position: 0,
+            word: word.to_owned(),
+            reading: Some(reading.to_owned()),
+        };
+
+        // The candidates are not known before the segment is analyzed
+        let res = server.send(resolve("Read", "reed")).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidReading)));
+        assert_eq!(diagnosed_rows(&mut received).await, vec![0]);
+
+        let res = server.send(resolve("Read", "rid")).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidReading)));
+        let res = server.send(resolve("it", "reed")).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidReading)));
+        let res = server.send(resolve("lead", "reed")).await.unwrap();
+        assert!(matches!(res, Err(ServerError::WordNotInSentence)));
+        server.send(resolve("Read", "reed")).await.unwrap().unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn freezing_reads_the_cached_analyses() {
+        let analyzer = Arc::new(fixture_analyzer());
+        let mut project = Project::new("freeze", "seed", &["a".to_owned(), "b".to_owned()]);
+        project.segments.push(Segment {
+            combo_index: 5,
+            ..Segment::new("hello world")
+        });
+        project.segments.push(Segment::new("never analyzed"));
+        sm::analyze(&*analyzer, &project, &project.segments[0])
+            .await
+            .unwrap();
+        let server = SmActor::new(analyzer).start();
+        server.send(Load { project }).await.unwrap().unwrap();
+
+        let freeze = |segment_position| FreezeSegments {
+            id: 0,
+            project_name: "freeze".to_owned(),
+            segment_position,
+        };
+        let res = server.send(freeze(Some(1))).await.unwrap();
+        assert!(matches!(res, Err(ServerError::SegmentNotAnalyzed)));
+        server.send(freeze(None)).await.unwrap().unwrap();
+
+        let projects = match server.send(ListProjects).await.unwrap() {
+            ServerRequest::ChangeListProjects { projects } => projects,
+            _ => unreachable!(),
+        };
+        let project = projects.iter().find(|p| p.name == "freeze").unwrap();
+        // The index is clamped to the combo which was frozen
+        assert_eq!(project.segments[0].combo_index, 1);
+        assert!(project.segments[0].frozen.is_some());
+        assert!(project.segments[1].frozen.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn combo_indexes_must_be_in_the_analysis() {
+        let analyzer = Arc::new(fixture_analyzer());
+        let mut project = Project::new("combos", "seed", &["a".to_owned(), "b".to_owned()]);
+        project.segments.push(Segment::new("hello world"));
+        project.segments.push(Segment::new("never analyzed"));
+        sm::analyze(&*analyzer, &project, &project.segments[0])
+            .await
+            .unwrap();
+        let server = SmActor::new(analyzer).start();
+        server.send(Load { project }).await.unwrap().unwrap();
+
+        let modify = |segment_position, new_combo_index| ModifySegmentComboIndex {
+            id: 0,
+            project_name: "combos".to_owned(),
+            segment_position,
+            new_combo_index,
+        };
+        let res = server.send(modify(0, 2)).await.unwrap();
+        assert!(matches!(res, Err(ServerError::InvalidComboIndex)));
+        server.send(modify(0, 1)).await.unwrap().unwrap();
+        // Checked once the segment is analyzed
+        server.send(modify(1, 2)).await.unwrap().unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn vocabularies_wait_for_the_videos() {
+        let (server, id, _) = connect().await;
+        let list = ListVocabulary {
+            id,
+            project_name: "test".to_owned(),
+        };
+        let res = server.send(list).await.unwrap();
+        assert!(matches!(res, Err(ServerError::VideosNotReady)));
+    }
+
+    #[actix_rt::test]
+    async fn unknown_projects_are_rejected() {
+        let (server, id, _) = connect().await;
+        let request = CheckCoverage {
+            id,
+            project_name: "other".to_owned(),
+            text: "hello".to_owned(),
+        };
+        let res = server.send(request).await.unwrap();
+        assert!(matches!(res, Err(ServerError::ProjectDoesNotExist)));
+    }
+}
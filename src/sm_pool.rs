@@ -0,0 +1,288 @@
+//! Pool of long-lived sm-interface processes.
+//!
+//! Workers are launched with `launch --server` and speak line-delimited json
+//! over stdin/stdout. Every request is answered by exactly one line:
+//!
+//! ```text
+//! > {"id": 1, "method": "analyze", "params": {"sentence": "...", "seed": "...", "urls": [...]}}
+//! < {"id": 1, "result": [[{"v": 0, "s": 1.2, "e": 1.3}, ...], ...]}
+//! > {"id": 2, "method": "analyze", "params": {...}}
+//! < {"id": 2, "error": {"word": "..."}}
+//! > {"id": 3, "method": "ping"}
+//! < {"id": 3, "result": "pong"}
+//! ```
+//!
+//! If a new worker exits or rejects the ping, the sm-interface build is considered
+//! too old to support the server mode and the pool stays disabled. A worker which
+//! is only slow to start is dropped, and the pool tries again after a delay.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex as SyncMutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{Mutex, Semaphore};
+
+const DEFAULT_POOL_SIZE: usize = 2;
+/// Idle workers are pinged before being reused after this delay
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Loading the models can take a while, new workers are given longer to answer
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Delay before starting a worker again after one did not start in time
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The pool is disabled, or sm-interface does not support the server mode
+    Unavailable,
+    Io(std::io::Error),
+    Timeout,
+    /// The worker answered something that is not a valid response
+    Protocol,
+}
+
+impl std::fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WorkerError::Unavailable => write!(f, "server mode unavailable"),
+            WorkerError::Io(e) => write!(f, "{}", e),
+            WorkerError::Timeout => write!(f, "request timed out"),
+            WorkerError::Protocol => write!(f, "invalid response"),
+        }
+    }
+}
+
+impl From<std::io::Error> for WorkerError {
+    fn from(e: std::io::Error) -> Self {
+        WorkerError::Io(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    method: &'a str,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+struct Worker {
+    // Kept so that the process is killed when the worker is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    last_used: Instant,
+}
+
+impl Worker {
+    fn spawn(mut command: Command) -> Result<Worker, WorkerError> {
+        let mut child = command
+            .arg("--server")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(WorkerError::Protocol)?;
+        let stdout = child.stdout.take().ok_or(WorkerError::Protocol)?;
+        Ok(Worker {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            last_used: Instant::now(),
+        })
+    }
+
+    async fn call(
+        &mut self,
+        id: u64,
+        method: &str,
+        params: Value,
+    ) -> Result<Result<Value, Value>, WorkerError> {
+        let mut line = serde_json::to_vec(&Request { id, method, params })
+            .map_err(|_| WorkerError::Protocol)?;
+        line.push(b'\n');
+        self.stdin.write_all(&line).await?;
+        self.stdin.flush().await?;
+
+        // Lines printed by the worker that are not responses are ignored
+        loop {
+            let mut answer = String::new();
+            if self.stdout.read_line(&mut answer).await? == 0 {
+                return Err(WorkerError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            match serde_json::from_str::<Response>(&answer) {
+                Ok(response) if response.id == id => {
+                    self.last_used = Instant::now();
+                    return match (response.result, response.error) {
+                        (_, Some(error)) => Ok(Err(error)),
+                        (Some(result), None) => Ok(Ok(result)),
+                        (None, None) => Err(WorkerError::Protocol),
+                    };
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// `Err(WorkerError::Unavailable)` if the worker does not know the ping method
+    async fn ping(&mut self, id: u64, timeout: Duration) -> Result<(), WorkerError> {
+        match tokio::time::timeout(timeout, self.call(id, "ping", Value::Null)).await {
+            Ok(Ok(Ok(_))) => Ok(()),
+            Ok(Ok(Err(_))) => Err(WorkerError::Unavailable),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(WorkerError::Timeout),
+        }
+    }
+}
+
+pub struct WorkerPool {
+    idle: Mutex<Vec<Worker>>,
+    permits: Semaphore,
+    command: fn() -> Command,
+    unsupported: AtomicBool,
+    /// Set when a new worker did not start in time
+    retry_at: SyncMutex<Option<Instant>>,
+    next_id: AtomicU64,
+}
+
+impl WorkerPool {
+    /// The number of workers is set with `SM_WORKERS`, 0 disables the pool
+    pub fn from_env(command: fn() -> Command) -> Self {
+        let size = std::env::var("SM_WORKERS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_POOL_SIZE);
+        WorkerPool {
+            idle: Mutex::new(Vec::with_capacity(size)),
+            permits: Semaphore::new(size),
+            command,
+            unsupported: AtomicBool::new(size == 0),
+            retry_at: SyncMutex::new(None),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Takes an idle healthy worker, or starts a new one
+    async fn checkout(&self) -> Result<Worker, WorkerError> {
+        loop {
+            let idle = self.idle.lock().await.pop();
+            match idle {
+                Some(mut worker) => {
+                    if worker.last_used.elapsed() < HEALTH_CHECK_INTERVAL
+                        || worker.ping(self.next_id(), PING_TIMEOUT).await.is_ok()
+                    {
+                        return Ok(worker);
+                    }
+                    println!("sm-interface worker failed its health check, restarting it");
+                }
+                None => {
+                    let mut worker = Worker::spawn((self.command)())?;
+                    return match worker.ping(self.next_id(), STARTUP_TIMEOUT).await {
+                        Ok(()) => Ok(worker),
+                        // The worker answered that it does not know the ping method
+                        Err(WorkerError::Unavailable) => {
+                            println!("sm-interface does not support the server mode, spawning one process per analysis");
+                            self.unsupported.store(true, Ordering::Relaxed);
+                            Err(WorkerError::Unavailable)
+                        }
+                        // The worker crashed or hung while starting, which may not last
+                        Err(e) => {
+                            println!(
+                                "sm-interface worker did not start: {}, trying again later",
+                                e
+                            );
+                            *self.retry_at.lock().unwrap() = Some(Instant::now() + RETRY_DELAY);
+                            Err(WorkerError::Unavailable)
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    /// Sends a request to a worker.
+    /// Returns `Ok(Err(_))` if the worker answered with an error object.
+    pub async fn call(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<Result<Value, Value>, WorkerError> {
+        if self.unsupported.load(Ordering::Relaxed) {
+            return Err(WorkerError::Unavailable);
+        }
+        if matches!(*self.retry_at.lock().unwrap(), Some(at) if at > Instant::now()) {
+            return Err(WorkerError::Unavailable);
+        }
+        let _permit = self.permits.acquire().await;
+
+        let mut worker = self.checkout().await?;
+        let id = self.next_id();
+        let response =
+            match tokio::time::timeout(REQUEST_TIMEOUT, worker.call(id, method, params)).await {
+                Ok(response) => response,
+                Err(_) => Err(WorkerError::Timeout),
+            };
+
+        // A worker which failed is dropped, hence killed, and replaced on next checkout
+        match &response {
+            Ok(_) => self.idle.lock().await.push(worker),
+            Err(e) => println!("sm-interface worker failed: {}", e),
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(command: fn() -> Command) -> WorkerPool {
+        WorkerPool {
+            idle: Mutex::new(vec![]),
+            permits: Semaphore::new(1),
+            command,
+            unsupported: AtomicBool::new(false),
+            retry_at: SyncMutex::new(None),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[actix_rt::test]
+    async fn crashed_workers_are_started_again_later() {
+        let pool = pool(|| shell("exit 1"));
+        assert!(pool.call("ping", Value::Null).await.is_err());
+        assert!(!pool.unsupported.load(Ordering::Relaxed));
+        assert!(pool.retry_at.lock().unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn unknown_ping_disables_the_pool() {
+        let pool = pool(|| shell(r#"read line; echo '{"id": 0, "error": "unknown method"}'"#));
+        assert!(pool.call("ping", Value::Null).await.is_err());
+        assert!(pool.unsupported.load(Ordering::Relaxed));
+    }
+}
@@ -118,6 +118,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSmSession {
                 Ok(ClientRequest::CreateSegment(req)) => {
                     transfer_id!(self, ctx, req, CreateSegment)
                 }
+                Ok(ClientRequest::CheckCoverage(req)) => {
+                    transfer_id!(self, ctx, req, CheckCoverage)
+                }
+                Ok(ClientRequest::ListVocabulary(req)) => {
+                    transfer_id!(self, ctx, req, ListVocabulary)
+                }
                 Ok(ClientRequest::ModifySegmentSentence(req)) => {
                     transfer_id!(self, ctx, req, ModifySegmentSentence)
                 }
@@ -127,6 +133,46 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSmSession {
                 Ok(ClientRequest::RemoveSegment(req)) => {
                     transfer_id!(self, ctx, req, RemoveSegment)
                 }
+                Ok(ClientRequest::ResolveAmbiguity(req)) => {
+                    transfer_id!(self, ctx, req, ResolveAmbiguity)
+                }
+                Ok(ClientRequest::SetPronunciation(req)) => {
+                    transfer_id!(self, ctx, req, SetPronunciation)
+                }
+                Ok(ClientRequest::ListCombos(req)) => {
+                    transfer_id!(self, ctx, req, ListCombos)
+                }
+                Ok(ClientRequest::ListPhonemAlternatives(req)) => {
+                    transfer_id!(self, ctx, req, ListPhonemAlternatives)
+                }
+                Ok(ClientRequest::OverridePhonem(req)) => {
+                    transfer_id!(self, ctx, req, OverridePhonem)
+                }
+                Ok(ClientRequest::RerollSegment(req)) => {
+                    transfer_id!(self, ctx, req, RerollSegment)
+                }
+                Ok(ClientRequest::FreezeSegments(req)) => {
+                    transfer_id!(self, ctx, req, FreezeSegments)
+                }
+                Ok(ClientRequest::UnfreezeSegments(req)) => {
+                    transfer_id!(self, ctx, req, UnfreezeSegments)
+                }
+                Ok(ClientRequest::SetNormalization(req)) => {
+                    transfer_id!(self, ctx, req, SetNormalization)
+                }
+                Ok(ClientRequest::SetLanguage(req)) => {
+                    transfer_id!(self, ctx, req, SetLanguage)
+                }
+                Ok(ClientRequest::ListLanguages(mut req)) => {
+                    req.id = self.id;
+                    transfer!(self, ctx, req)
+                }
+                Ok(ClientRequest::Search(req)) => {
+                    transfer_id!(self, ctx, req, Search)
+                }
+                Ok(ClientRequest::CancelRender(req)) => {
+                    transfer_id!(self, ctx, req, CancelRender)
+                }
                 Ok(ClientRequest::Export(req)) => {
                     transfer_id!(self, ctx, req, Export)
                 }
@@ -0,0 +1,70 @@
+//! Bundled lists of synonyms, used to suggest words the sources can say
+
+use crate::normalize::Lang;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+
+struct Thesaurus {
+    groups: Vec<Vec<&'static str>>,
+    /// Groups of each word
+    index: HashMap<&'static str, Vec<usize>>,
+}
+
+fn load(list: &'static str) -> Thesaurus {
+    let groups: Vec<Vec<&str>> = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(',').map(str::trim).collect())
+        .collect();
+    let mut index: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, group) in groups.iter().enumerate() {
+        for word in group {
+            index.entry(word).or_default().push(i);
+        }
+    }
+    Thesaurus { groups, index }
+}
+
+static EN: Lazy<Thesaurus> = Lazy::new(|| load(include_str!("synonyms/en.txt")));
+static FR: Lazy<Thesaurus> = Lazy::new(|| load(include_str!("synonyms/fr.txt")));
+
+/// Synonyms of a lowercase word, for the language code `language`
+pub fn synonyms(word: &str, language: Option<&str>) -> Vec<&'static str> {
+    let thesaurus = match Lang::from_code(language) {
+        Lang::En => &*EN,
+        Lang::Fr => &*FR,
+    };
+    let mut seen = HashSet::new();
+    thesaurus
+        .index
+        .get(word)
+        .into_iter()
+        .flatten()
+        .flat_map(|&group| thesaurus.groups[group].iter().copied())
+        .filter(|&w| w != word && seen.insert(w))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synonyms_of_every_group_without_the_word() {
+        // "great" belongs to two groups, and is only given once
+        let big = synonyms("big", Some("en"));
+        assert_eq!(big, vec!["large", "huge", "great", "enormous", "giant"]);
+        let great = synonyms("great", None);
+        assert_eq!(great.iter().filter(|w| **w == "good").count(), 1);
+        assert!(!great.contains(&"great"));
+        assert!(great.contains(&"giant") && great.contains(&"excellent"));
+    }
+
+    #[test]
+    fn synonyms_follow_the_language() {
+        assert!(synonyms("petit", Some("fr")).contains(&"minuscule"));
+        assert!(synonyms("petit", Some("en")).is_empty());
+        assert!(synonyms("unknown", Some("fr")).is_empty());
+    }
+}